@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::thread;
+use crate::cancel;
+use crate::config;
+use crate::creds;
+use crate::progress;
+
+struct Request {
+    git_url: creds::GitUrl,
+    url: String,
+    repo_path: String,
+    ask: Ask,
+}
+
+enum Ask {
+    Cred { is_ssh: bool, reuse: bool, reply: mpsc::Sender<Option<creds::GitCred>> },
+    Username(mpsc::Sender<Option<String>>),
+}
+
+// Handle to the thread that owns the credential storage. Fetches ask it for
+// credentials instead of locking the storage, so a prompt only blocks the
+// repos that are waiting on it.
+#[derive(Clone)]
+pub struct Broker {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Broker {
+    pub fn spawn(storage: creds::Storage, progress: progress::Progress, cancel: cancel::Cancel) -> Broker {
+        let (requests, rx) = mpsc::channel();
+        thread::spawn(move || serve(storage, rx, progress, cancel));
+        Broker { requests }
+    }
+
+    // None means the credential needs a prompt that is not allowed. `reuse` asks
+    // for the repo's current credential, when a new fetch attempt starts rather
+    // than the last credential being rejected.
+    pub fn request(&self, git_url: creds::GitUrl, url: &str, repo_path: &str, is_ssh: bool, reuse: bool) -> Option<creds::GitCred> {
+        let (reply, response) = mpsc::channel();
+        self.requests.send(Request {
+            git_url,
+            url: url.to_string(),
+            repo_path: repo_path.to_string(),
+            ask: Ask::Cred { is_ssh, reuse, reply },
+        }).expect("credential broker stopped");
+        response.recv().expect("credential broker dropped a request")
+    }
+
+    // The username for a remote whose url has none. None means it needs a
+    // prompt that is not allowed.
+    pub fn username(&self, git_url: creds::GitUrl, url: &str, repo_path: &str) -> Option<String> {
+        let (reply, response) = mpsc::channel();
+        self.requests.send(Request {
+            git_url,
+            url: url.to_string(),
+            repo_path: repo_path.to_string(),
+            ask: Ask::Username(reply),
+        }).expect("credential broker stopped");
+        response.recv().expect("credential broker dropped a request")
+    }
+}
+
+// Requests with the same credential type, and the same repo, org or host as
+// --share allows, are identical for the purposes of a first attempt, so they
+// can share an answer. Usernames are shared by the storage itself.
+fn dedupe_key(request: &Request, share: &config::Share) -> Option<(String, bool)> {
+    match request.ask {
+        Ask::Cred { is_ssh, .. } => Some((creds::answer_key(share, &request.git_url)?, is_ssh)),
+        Ask::Username(_) => None,
+    }
+}
+
+fn serve(mut storage: creds::Storage, rx: mpsc::Receiver<Request>, progress: progress::Progress, cancel: cancel::Cancel) {
+    let mut queue: VecDeque<Request> = VecDeque::new();
+    loop {
+        if queue.is_empty() {
+            match rx.recv() {
+                Ok(request) => queue.push_back(request),
+                Err(_) => return,
+            }
+        }
+        let request = queue.pop_front().expect("queue is not empty");
+        // Once cancelled nobody is asked anything more. The fetches stop on
+        // their own, whatever the answer.
+        if cancel.is_cancelled() {
+            match request.ask {
+                Ask::Cred { reply, .. } => { let _ = reply.send(None); },
+                Ask::Username(reply) => { let _ = reply.send(None); },
+            }
+            continue;
+        }
+        let key = dedupe_key(&request, storage.share());
+        let (is_ssh, reply) = match request.ask {
+            Ask::Cred { reuse: true, reply, .. } if storage.has_cred(&request.git_url, &request.repo_path) => {
+                let _ = reply.send(storage.reuse_active(&request.git_url, &request.repo_path));
+                continue;
+            },
+            Ask::Cred { is_ssh, reply, .. } => (is_ssh, reply),
+            Ask::Username(reply) => {
+                let pause = (progress.pause(), cancel.prompting());
+                let username = storage.get_username(&request.git_url, &request.repo_path, &request.url);
+                drop(pause);
+                let _ = reply.send(username);
+                continue;
+            },
+        };
+        let first_attempt = !storage.has_cred(&request.git_url, &request.repo_path);
+
+        // Resolving may prompt, which must not be drawn over.
+        let pause = (progress.pause(), cancel.prompting());
+        let cred = storage.get_cred(
+            request.git_url.clone(),
+            request.repo_path.clone(),
+            is_ssh,
+            request.url.clone(),
+        );
+        drop(pause);
+
+        // Everyone who asked while the user was typing is now waiting here.
+        queue.extend(rx.try_iter());
+
+        if let Some(cred) = &cred {
+            if first_attempt && key.is_some() {
+                let (waiters, rest): (VecDeque<_>, VecDeque<_>) = queue.into_iter().partition(|x| {
+                    dedupe_key(x, storage.share()) == key && !storage.has_cred(&x.git_url, &x.repo_path)
+                });
+                for waiter in waiters {
+                    storage.assign(&waiter.git_url, waiter.repo_path.clone(), cred.clone());
+                    if let Ask::Cred { reply, .. } = waiter.ask {
+                        let _ = reply.send(Some(cred.clone()));
+                    }
+                }
+                queue = rest;
+            }
+        }
+
+        // The fetch may have given up waiting, in which case nobody is listening.
+        let _ = reply.send(cred);
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Set once the user asked to stop. Nothing new is started after that, and
+// running repos stop at the next point where stopping leaves them untouched.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel {
+    cancelled: Arc<AtomicBool>,
+    prompting: Arc<AtomicBool>,
+}
+
+// Marks a prompt as open until dropped. A prompt can not be interrupted
+// without losing the terminal's settings, so it has to be answered instead.
+pub struct Prompting<'a>(&'a Cancel);
+
+impl Drop for Prompting<'_> {
+    fn drop(&mut self) {
+        self.0.prompting.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn prompting(&self) -> Prompting<'_> {
+        self.prompting.store(true, Ordering::SeqCst);
+        Prompting(self)
+    }
+
+    fn is_prompting(&self) -> bool {
+        self.prompting.load(Ordering::SeqCst)
+    }
+}
+
+// The first Ctrl-C cancels the run so it can still print its summary. A second
+// one is for when something refuses to stop, e.g. a hung connection.
+pub fn on_ctrl_c() -> Cancel {
+    let cancel = Cancel::default();
+    let handler_cancel = cancel.clone();
+    let presses = AtomicUsize::new(0);
+    let installed = ctrlc::set_handler(move || {
+        if presses.fetch_add(1, Ordering::SeqCst) == 0 {
+            if handler_cancel.is_prompting() {
+                eprintln!("\nStopping after the repos in progress. Press Enter to skip the prompt, or Ctrl-C again to quit now.");
+            } else {
+                eprintln!("\nStopping after the repos in progress. Press Ctrl-C again to quit now.");
+            }
+            handler_cancel.cancel();
+        } else {
+            eprintln!("\nQuitting without a summary.");
+            std::process::exit(130);
+        }
+    });
+    if let Err(err) = installed {
+        eprintln!("Could not set up Ctrl-C handling, an interrupt will stop upgit immediately: {}", err);
+    }
+    cancel
+}
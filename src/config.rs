@@ -2,23 +2,48 @@ use clap::{Arg, App, ArgMatches};
 use std::env;
 use text_io::read;
 use shellexpand;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::prelude::*;
+use std::time::Duration;
 use rpassword;
-use std::process::Command;
-use std::fs::File;
-use std::os::unix::fs::PermissionsExt;
 use crate::string_ops;
+use crate::end;
+use crate::keys;
+use crate::known_hosts;
+use crate::pool;
+use crate::post_update;
+use crate::pull;
+use crate::state;
+use crate::tokens;
+use crate::watch;
 
 #[derive(Debug)]
 pub struct Config {
     pub ssh: HashMap<String, String>, // path, pass
     pub plain: HashMap<String, String>,
+    pub users: HashMap<String, String>, // host, username for https remotes without one
+    pub tokens: tokens::Tokens,
     pub default_plain: Option<String>,
     pub default_ssh: (String, Option<String>),
     pub git_dirs: Vec<String>,
     pub share: Share,
+    pub host_key_check: known_hosts::Policy,
+    pub non_interactive: bool,
+    pub jobs: usize,
+    pub host_jobs: pool::HostLimits,
+    pub fetch_timeout: Option<Duration>,
+    pub run_timeout: Option<Duration>,
+    pub retries: u32,
+    pub log_limit: usize,
+    pub watch: watch::Watch,
+    pub post_update: post_update::PostUpdate,
+    pub hooks: bool,
+    pub pull: pull::Overrides,
+    pub rerun: state::Rerun,
+    pub state_file: String,
+    pub format: end::Format,
+    pub fail_on: Option<HashSet<end::Status>>,
 }
 
 fn prompt_confirm(prompt: String, required: bool, sensitive: bool) -> String {
@@ -53,12 +78,6 @@ fn prompt_confirm(prompt: String, required: bool, sensitive: bool) -> String {
     response
 }
 
-enum SshVerify {
-    Dunno,
-    Good,
-    Bad,
-}
-
 #[derive(Debug, Clone, PartialEq,  Eq, PartialOrd, Ord)]
 pub enum Share {
     Never,
@@ -68,86 +87,320 @@ pub enum Share {
     Domain,
 }
 
-fn verify_ssh_pass(private_key_path: &String, passphrase: &String) -> SshVerify {
-    let tmp_path = "/tmp/echo-upgit-pw";
-    let tmp_var = "TMP_UPGIT_PW";
-    match File::create(tmp_path) {
-        Ok(mut file) => {
-            match file.write_all(format!("#!/usr/bin/env sh\necho ${}\n", tmp_var).as_bytes()) {
-                Ok(_) => {
-                    match file.metadata() {
-                        Ok(metadata) => {
-                            let mut permissions = metadata.permissions();
-                            permissions.set_mode(0o700);
-                            match std::fs::set_permissions(tmp_path, permissions) {
-                                Ok(_) => {},
-                                Err(_) => return SshVerify::Dunno,
-                            }
-                        }
-                        Err(_) => return SshVerify::Dunno,
-                    }
-                },
-                Err(_) => return SshVerify::Dunno,
-            };
-        }
-        Err(_) => return SshVerify::Dunno,
-    };
-
-    let cmd = Command::new("ssh-keygen")
-        .arg("-y")
-        .arg("-f")
-        .arg(private_key_path)
-        .env(tmp_var, passphrase)
-        .env("SSH_ASKPASS", tmp_path)
-        .output();
-
-    if let Ok(output) = cmd {
-        if let Ok(stdout) = std::str::from_utf8(&output.stdout) {
-            if stdout.len() > 0 && output.status.success() {
-                return SshVerify::Good;
-            }
-            return SshVerify::Bad;
-        };
-    };
-    SshVerify::Good
-}
-
 pub fn prompt_ssh_pass(private_key_path: &String) -> String {
+    if !keys::needs_passphrase(private_key_path) {
+        return String::from("");
+    }
     let response = match rpassword::read_password_from_tty(Some(&format!("Enter password for ssh key {} (blank for none): ", private_key_path))) {
         Ok(x) => x,
         // If there is no tty, for example in e2e tests,
         // we should at least allow a potentially valid no password scenario
-        _ => String::from(""),
+        _ => return String::from(""),
     };
-    match verify_ssh_pass(&private_key_path, &response) {
-        SshVerify::Good => return response,
-        SshVerify::Dunno => {
+    match keys::verify_passphrase(private_key_path, &response) {
+        keys::SshVerify::Good => response,
+        keys::SshVerify::Dunno => {
             print!("Confirm: ");
-            match io::stdout().flush() {
-                Ok(_) => {},
-                _ => {}
-            };
+            let _ = io::stdout().flush();
             let response_confirm: String = read!("{}\n");
             if response == response_confirm {
                 return response
             }
-            return prompt_ssh_pass(private_key_path);
+            prompt_ssh_pass(private_key_path)
         },
-        SshVerify::Bad => return prompt_ssh_pass(private_key_path),
+        keys::SshVerify::Bad => prompt_ssh_pass(private_key_path),
     }
 }
 
 fn relative_to_absolute_path(x: &str) -> String {
     let path_str: String = shellexpand::tilde(x).into_owned();
-    std::fs::canonicalize(&path_str)
+    let path = std::fs::canonicalize(&path_str)
         .or_else(|_| std::fs::read_link(x))
-        .expect(&format!("\"{}\" was not a canonical path or symlink", path_str))
-        .to_str()
-        .expect(&format!("\"{}\"was not a path", path_str))
-        .to_string()
+        .unwrap_or_else(|_| {
+            eprintln!("\"{}\" was not a canonical path or symlink", path_str);
+            std::process::exit(1);
+        });
+    match path.to_str() {
+        Some(path) => path.to_string(),
+        None => {
+            eprintln!("\"{}\" was not a valid unicode path", path_str);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn get_jobs(matches: &ArgMatches) -> usize {
+    let jobs_str = matches.value_of("jobs").map(String::from).or_else(|| env::var("UPGIT_JOBS").ok());
+    match jobs_str.map(|x| x.parse::<usize>()) {
+        Some(Ok(jobs)) if jobs > 0 => jobs,
+        Some(_) => {
+            eprintln!("--jobs must be a positive number");
+            std::process::exit(1);
+        },
+        None => pool::default_workers(),
+    }
+}
+
+fn get_retries(matches: &ArgMatches) -> u32 {
+    let retries_str = matches.value_of("retries").map(String::from).or_else(|| env::var("UPGIT_RETRIES").ok());
+    match retries_str.map(|x| x.parse::<u32>()) {
+        Some(Ok(retries)) => retries,
+        Some(Err(_)) => {
+            eprintln!("--retries must be a number");
+            std::process::exit(1);
+        },
+        None => 2,
+    }
+}
+
+fn get_log_limit(matches: &ArgMatches) -> usize {
+    let limit_str = matches.value_of("log-limit").map(String::from).or_else(|| env::var("UPGIT_LOG_LIMIT").ok());
+    match limit_str.map(|x| x.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            eprintln!("--log-limit must be a number");
+            std::process::exit(1);
+        },
+        None => 10,
+    }
+}
+
+// A timeout of 0 seconds means no timeout.
+fn get_timeout(matches: &ArgMatches, name: &str, env_name: &str, default: u64) -> Option<Duration> {
+    let secs_str = matches.value_of(name).map(String::from).or_else(|| env::var(env_name).ok());
+    let secs = match secs_str.map(|x| x.parse::<u64>()) {
+        Some(Ok(secs)) => secs,
+        Some(Err(_)) => {
+            eprintln!("--{} must be a number of seconds", name);
+            std::process::exit(1);
+        },
+        None => default,
+    };
+    if secs == 0 { None } else { Some(Duration::from_secs(secs)) }
+}
+
+fn parse_host_jobs<'a>(entries: impl Iterator<Item = &'a str>) -> pool::HostLimits {
+    let mut limits = pool::HostLimits::default();
+    for entry in entries {
+        let parsed = entry.rsplit_once('=')
+            .and_then(|(host, jobs)| jobs.parse::<usize>().ok().filter(|x| *x > 0).map(|jobs| (host, jobs)));
+        match parsed {
+            Some(("*", jobs)) => limits.default = Some(jobs),
+            Some((host, jobs)) => { limits.per_host.insert(host.to_lowercase(), jobs); },
+            None => {
+                eprintln!("--host-jobs expects <domain>=<positive number>, got \"{}\"", entry);
+                std::process::exit(1);
+            },
+        }
+    }
+    limits
+}
+
+fn parse_users<'a>(entries: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    let mut users = HashMap::new();
+    for entry in entries {
+        match entry.split_once('=') {
+            Some((host, user)) if !host.is_empty() && !user.is_empty() => {
+                users.insert(host.to_lowercase(), user.to_string());
+            },
+            _ => {
+                eprintln!("--user expects <domain>=<username>, got \"{}\"", entry);
+                std::process::exit(1);
+            },
+        }
+    }
+    users
+}
+
+fn get_users(matches: &ArgMatches) -> HashMap<String, String> {
+    if let Some(entries) = matches.values_of("user") {
+        return parse_users(entries);
+    }
+
+    if let Ok(string) = env::var("UPGIT_USER") {
+        return parse_users(string.split(","));
+    }
+
+    HashMap::new()
+}
+
+// UPGIT_TOKEN_<host> env vars, with hosts in the tokens file winning.
+fn get_tokens(matches: &ArgMatches) -> tokens::Tokens {
+    let mut tokens = tokens::Tokens::from_env(env::vars());
+    let path = matches.value_of("tokens").map(String::from).or_else(|| env::var("UPGIT_TOKENS").ok());
+    if let Some(path) = path {
+        let path = shellexpand::tilde(&path).into_owned();
+        let added = std::fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| tokens.add_file(&contents));
+        if let Err(err) = added {
+            eprintln!("Could not read tokens file \"{}\": {}", path, err);
+            std::process::exit(1);
+        }
+    }
+    tokens
+}
+
+fn get_host_jobs(matches: &ArgMatches) -> pool::HostLimits {
+    if let Some(entries) = matches.values_of("host-jobs") {
+        return parse_host_jobs(entries);
+    }
+
+    if let Ok(string) = env::var("UPGIT_HOST_JOBS") {
+        return parse_host_jobs(string.split(","));
+    }
+
+    pool::HostLimits::default()
+}
+
+fn get_rerun(matches: &ArgMatches) -> state::Rerun {
+    if matches.is_present("only-failed") {
+        return state::Rerun::OnlyFailed;
+    }
+    if matches.is_present("resume") {
+        return state::Rerun::Resume;
+    }
+    // Flags win over env vars, so only look at those when neither flag was given.
+    if env::var("UPGIT_ONLY_FAILED").is_ok() {
+        return state::Rerun::OnlyFailed;
+    }
+    if env::var("UPGIT_RESUME").is_ok() {
+        return state::Rerun::Resume;
+    }
+    state::Rerun::All
+}
+
+fn get_state_file(matches: &ArgMatches) -> String {
+    if let Some(path) = matches.value_of("state-file") {
+        return shellexpand::tilde(path).into_owned();
+    }
+
+    if let Ok(path) = env::var("UPGIT_STATE_FILE") {
+        return shellexpand::tilde(&path).into_owned();
+    }
+
+    state::default_path()
+}
+
+fn str_to_format(x: &str) -> end::Format {
+    if x == "json" { end::Format::Json }
+    else { end::Format::Text }
+}
+
+fn get_format(matches: &ArgMatches) -> end::Format {
+    if matches.occurrences_of("format") > 0 {
+        if let Some(format_str) = matches.value_of("format") {
+            return str_to_format(format_str);
+        }
+    }
+
+    if let Ok(format_str) = env::var("UPGIT_FORMAT") {
+        return str_to_format(&format_str);
+    }
+
+    end::Format::Text
+}
+
+fn parse_fail_on<'a>(names: impl Iterator<Item = &'a str>) -> HashSet<end::Status> {
+    names.map(|name| match end::Status::from_name(name.trim()) {
+        Some(status) => status,
+        None => {
+            eprintln!("--fail-on expects statuses as named in the JSON report, e.g. failed_fetch, got \"{}\"", name);
+            std::process::exit(1);
+        },
+    }).collect()
+}
+
+fn get_fail_on(matches: &ArgMatches) -> Option<HashSet<end::Status>> {
+    if let Some(names) = matches.values_of("fail-on") {
+        return Some(parse_fail_on(names));
+    }
+
+    if let Ok(string) = env::var("UPGIT_FAIL_ON") {
+        return Some(parse_fail_on(string.split(",")));
+    }
+
+    None
+}
+
+fn get_watch(matches: &ArgMatches) -> watch::Watch {
+    if let Some(entries) = matches.values_of("watch") {
+        return watch::Watch::parse(entries);
+    }
+
+    if let Ok(string) = env::var("UPGIT_WATCH") {
+        return watch::Watch::parse(string.split(","));
+    }
+
+    watch::Watch::default()
+}
+
+fn get_post_update(matches: &ArgMatches) -> post_update::PostUpdate {
+    let jobs_str = matches.value_of("run-jobs").map(String::from).or_else(|| env::var("UPGIT_RUN_JOBS").ok());
+    let jobs = match jobs_str.map(|x| x.parse::<usize>()) {
+        Some(Ok(jobs)) if jobs > 0 => jobs,
+        Some(_) => {
+            eprintln!("--run-jobs must be a positive number");
+            std::process::exit(1);
+        },
+        None => 1,
+    };
+
+    // Commands often contain commas, so env var ones are one per line.
+    let env_entries = env::var("UPGIT_RUN").ok();
+    let parsed = match (matches.values_of("run"), &env_entries) {
+        (Some(entries), _) => post_update::PostUpdate::parse(entries, jobs),
+        (None, Some(string)) => post_update::PostUpdate::parse(string.lines(), jobs),
+        (None, None) => post_update::PostUpdate::parse(std::iter::empty(), jobs),
+    };
+    parsed.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
+}
+
+// A flag, or its env var holding a value git would accept for the setting.
+fn get_pull_setting<T>(
+    matches: &ArgMatches,
+    flags: &[(&str, T)],
+    env_name: &str,
+    parse: fn(&str) -> Option<T>,
+) -> Option<T> where T: Copy {
+    if let Some((_, value)) = flags.iter().find(|(flag, _)| matches.is_present(flag)) {
+        return Some(*value);
+    }
+    let string = env::var(env_name).ok()?;
+    match parse(&string) {
+        Some(value) => Some(value),
+        None => {
+            eprintln!("{} has an invalid value: {}", env_name, string);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn get_pull(matches: &ArgMatches) -> pull::Overrides {
+    pull::Overrides {
+        rebase: get_pull_setting(matches, &[("rebase", true), ("no-rebase", false)], "UPGIT_REBASE", pull::parse_rebase),
+        ff: get_pull_setting(
+            matches,
+            &[("ff", pull::FastForward::Allow), ("no-ff", pull::FastForward::Never), ("ff-only", pull::FastForward::Only)],
+            "UPGIT_FF",
+            pull::parse_ff,
+        ),
+        prune: get_pull_setting(matches, &[("prune", true), ("no-prune", false)], "UPGIT_PRUNE", pull::parse_bool),
+    }
+}
+
+fn get_hooks(matches: &ArgMatches) -> bool {
+    !(matches.is_present("no-hooks") || env::var("UPGIT_NO_HOOKS").is_ok())
+}
+
+fn get_non_interactive(matches: &ArgMatches) -> bool {
+    matches.is_present("non-interactive") || env::var("UPGIT_NON_INTERACTIVE").is_ok()
 }
 
-fn get_git_dirs(matches: &ArgMatches) -> Vec<String> {
+fn get_git_dirs(matches: &ArgMatches, non_interactive: bool) -> Vec<String> {
     let git_dirs_args: Vec<_> = matches.values_of("git-dirs").unwrap_or_default().map(|x| String::from(x)).collect();
     if git_dirs_args.len() > 0 {
         return git_dirs_args
@@ -160,6 +413,11 @@ fn get_git_dirs(matches: &ArgMatches) -> Vec<String> {
         }
     }
 
+    if non_interactive {
+        eprintln!("Git directories were not provided via $UPGIT_GIT_DIRS or CLI, exiting");
+        std::process::exit(1);
+    }
+
     println!("Git directories were not provided via $UPGIT_GIT_DIRS or CLI. Provide space separated list via stdin:");
     let git_dirs_str: String = read!("{}\n");
     if git_dirs_str.len() == 0 {
@@ -172,21 +430,24 @@ fn get_git_dirs(matches: &ArgMatches) -> Vec<String> {
     git_dirs 
 }
 
-fn get_default_ssh(matches: &ArgMatches) -> (String, Option<String>) {
-    let key_path = match matches.value_of("default-ssh") {
-        Some(path) => {
-            if path == String::from("") {
-                format!("{}/.ssh/id_rsa", env::var("HOME").expect("Unable to find HOME env var"))
-            } else {
-                path.to_string()
-            }
-        },
-        None => format!(""),
+fn get_default_ssh(matches: &ArgMatches, non_interactive: bool) -> (String, Option<String>) {
+    let env_path = env::var("UPGIT_DEFAULT_SSH");
+    if !matches.is_present("default-ssh") && env_path.is_err() {
+        return (String::from(""), None);
+    }
+
+    let key_path = match env_path {
+        Ok(path) if !path.is_empty() => path,
+        _ => keys::default_key_path(),
     };
 
-    let key_pass = if matches.is_present("default-ssh") || env::var("UPGIT_DEFAULT_SSH").is_ok() {
+    if non_interactive && keys::needs_passphrase(&key_path) {
+        eprintln!("Ignoring default ssh key {}, it needs a passphrase and upgit is running non-interactively", key_path);
+        return (String::from(""), None);
+    }
 
-        let response = prompt_confirm(format!("Enter default ssh key pass (blank for none): "), false, true);
+    let key_pass = if keys::needs_passphrase(&key_path) {
+        let response = prompt_confirm(format!("Enter default ssh key pass for {} (blank for none): ", key_path), false, true);
         string_ops::str_to_opt(response)
     } else {
         None
@@ -195,8 +456,12 @@ fn get_default_ssh(matches: &ArgMatches) -> (String, Option<String>) {
     (key_path, key_pass)
 }
 
-fn get_default_plain(matches: &ArgMatches) -> Option<String> {
+fn get_default_plain(matches: &ArgMatches, non_interactive: bool) -> Option<String> {
     if matches.is_present("default-plain") || env::var("UPGIT_DEFAULT_PLAIN").is_ok() {
+        if non_interactive {
+            eprintln!("Ignoring default plaintext password, it needs a prompt and upgit is running non-interactively");
+            return None;
+        }
         return Some(prompt_confirm(format!("Enter default plaintext authentication method pass (blank for none): "), true, true));
     };
 
@@ -224,16 +489,45 @@ fn get_share(matches: &ArgMatches) -> Share {
     Share::Defaults
 }
 
-fn get_ssh_keys(matches: &ArgMatches) -> HashMap<String, String> {
+fn str_to_host_key_policy(x: &str) -> known_hosts::Policy {
+    if x == "accept-new" { known_hosts::Policy::AcceptNew }
+    else if x == "off" { known_hosts::Policy::Off }
+    else { known_hosts::Policy::Strict }
+}
+
+fn get_host_key_check(matches: &ArgMatches) -> known_hosts::Policy {
+    if matches.occurrences_of("host-key-check") > 0 {
+        if let Some(policy_str) = matches.value_of("host-key-check") {
+            return str_to_host_key_policy(policy_str);
+        }
+    }
+
+    if let Ok(policy_str) = env::var("UPGIT_HOST_KEY_CHECK") {
+        return str_to_host_key_policy(&policy_str);
+    }
+
+    known_hosts::Policy::Strict
+}
+
+// Without a prompt, only keys that have no passphrase can be used.
+fn usable_ssh_key(path: &str, non_interactive: bool) -> bool {
+    if non_interactive && keys::needs_passphrase(path) {
+        eprintln!("Ignoring ssh key {}, it needs a passphrase and upgit is running non-interactively", path);
+        return false;
+    }
+    true
+}
+
+fn get_ssh_keys(matches: &ArgMatches, non_interactive: bool) -> HashMap<String, String> {
     if let Some(key_paths) = matches.values_of("ssh") {
-        return key_paths.map(|path| {(
+        return key_paths.filter(|path| usable_ssh_key(path, non_interactive)).map(|path| {(
             path.to_string(),
             prompt_ssh_pass(&path.to_string()),
         )}).collect();
     };
 
     if let Ok(string) = env::var("UPGIT_SSH") {
-        return string.split(",").map(|path| {(
+        return string.split(",").filter(|path| usable_ssh_key(path, non_interactive)).map(|path| {(
             path.to_string(),
             prompt_ssh_pass(&path.to_string()),
         )}).collect();
@@ -242,7 +536,12 @@ fn get_ssh_keys(matches: &ArgMatches) -> HashMap<String, String> {
     HashMap::new()
 }
 
-fn get_plaintexts(matches: &ArgMatches) -> HashMap<String, String> {
+fn get_plaintexts(matches: &ArgMatches, non_interactive: bool) -> HashMap<String, String> {
+    if non_interactive && (matches.is_present("plain") || env::var("UPGIT_PLAIN").is_ok()) {
+        eprintln!("Ignoring plaintext urls, their passwords need a prompt and upgit is running non-interactively");
+        return HashMap::new();
+    }
+
     if let Some(user_urls) = matches.values_of("plain") {
         return user_urls.map(|user_url| {(
             user_url.to_string(),
@@ -274,6 +573,20 @@ pub fn new() -> Config {
             .number_of_values(1)
             .long_help("Git repo https url with username. For example, `--plain https://neallred@bitbucket.org/neallred/allredlib-data-backup.git`. For each time this option is passed, user will be prompted for a password. Env var is comma separated UPGIT_PLAIN.")
         )
+        .arg(
+            Arg::with_name("user")
+            .long("user")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long_help("Username for https remotes on a host whose urls do not include one, as <domain>=<username>. For example, `--user bitbucket.org=neallred`. Without it, the username comes from git's credential.<url>.username, a credential helper, or a prompt, and is shared between repos as --share allows. Env var is comma separated UPGIT_USER.")
+        )
+        .arg(
+            Arg::with_name("tokens")
+            .long("tokens")
+            .takes_value(true)
+            .long_help("File of personal access tokens for https remotes, one <domain>=<token> per line, for forges that do not take account passwords. A token is only used over https for its own host, which must match exactly for tokens in the file, with the username that host expects (`x-access-token` for GitHub, `oauth2` for GitLab, `x-token-auth` for Bitbucket), or the remote's own username elsewhere. Tokens can also be given as env vars named UPGIT_TOKEN_ followed by the domain with `.` written as `_` and `-` as `__`, e.g. UPGIT_TOKEN_github_com or UPGIT_TOKEN_git__corp_io for git-corp.io. Lines in the file win over env vars. Env var for the file is UPGIT_TOKENS.")
+        )
         .arg(
             Arg::with_name("ssh")
             .long("ssh")
@@ -291,7 +604,7 @@ pub fn new() -> Config {
         .arg(
             Arg::with_name("default-ssh")
             .long("default-ssh")
-            .long_help("Default password to use for ssh keys. User will be prompted for the password. Env var is UPGIT_DEFAULT_SSH set to path of key (or empty, in which case $HOME/.ssh/id_rsa is assumed).")
+            .long_help("Default password to use for ssh keys. User will be prompted for the password. Env var is UPGIT_DEFAULT_SSH set to path of key (or empty, in which case the first standard key found in $HOME/.ssh is used, e.g. id_ed25519, id_ecdsa or id_rsa). Only prompts if the key is encrypted.")
         )
         .arg(
             Arg::with_name("share")
@@ -299,7 +612,169 @@ pub fn new() -> Config {
             .takes_value(true)
             .possible_values(&["none", "default", "duplicate", "org", "domain"])
             .default_value("default")
-            .long_help("Degree to which credentials may reused between repos needing auth. Each level is additive. `none` means no credential reuse between repos, and defaults are ignored. `default` means default provided credentials may be reused. `duplicate` means defaults, plus multiple copies of a repo can reuse each other's credential. `org` means duplicate, plus upgit will infer a matching org by looking at the second to last url path segment (e.g. `neallred` in https://github.com/neallred/upgit`). `domain` means reusing when user and url domain match. Env var is UPGIT_SHARE.")
+            .long_help("Degree to which credentials may reused between repos needing auth. Each level is additive. `none` means no credential reuse between repos, and defaults are ignored. `default` means default provided credentials may be reused. `duplicate` means defaults, plus multiple copies of a repo can reuse each other's credential, even when cloned through different urls such as https and ssh, as long as the credential is of the kind the url needs, the url is not less secure (e.g. http after https) and it names the same user or none. `org` means duplicate, plus upgit will infer a matching org by looking at the second to last url path segment (e.g. `neallred` in https://github.com/neallred/upgit`). `domain` means reusing when user and url domain match. Usernames looked up for https remotes without one are reused at the same levels. Env var is UPGIT_SHARE.")
+        )
+        .arg(
+            Arg::with_name("host-key-check")
+            .long("host-key-check")
+            .takes_value(true)
+            .possible_values(&["strict", "accept-new", "off"])
+            .default_value("strict")
+            .long_help("How ssh host keys are checked against $HOME/.ssh/known_hosts and /etc/ssh/ssh_known_hosts, including hashed entries. `strict` skips repos whose host is unknown or whose host key changed. `accept-new` records unknown hosts in $HOME/.ssh/known_hosts, but still skips changed host keys. `off` does no checking. Env var is UPGIT_HOST_KEY_CHECK.")
+        )
+        .arg(
+            Arg::with_name("jobs")
+            .long("jobs")
+            .short("j")
+            .takes_value(true)
+            .long_help("Maximum number of repos updated at the same time, across all git dirs. Defaults to the number of CPUs. Env var is UPGIT_JOBS.")
+        )
+        .arg(
+            Arg::with_name("host-jobs")
+            .long("host-jobs")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long_help("Maximum number of repos fetched at the same time from one host, as <domain>=<number>. For example, `--host-jobs git.megacorp.com=4`. Use `*` as the domain to limit every host not otherwise listed. Repos on other hosts are not held back. Env var is comma separated UPGIT_HOST_JOBS.")
+        )
+        .arg(
+            Arg::with_name("timeout")
+            .long("timeout")
+            .takes_value(true)
+            .long_help("Seconds a single repo's fetch may take before it is aborted and reported as timed out. Time spent waiting on a credential prompt is not counted. A fetch stuck with no data arriving is given up on 10 seconds later, without touching the repo. Defaults to 300, and 0 means no limit. Env var is UPGIT_TIMEOUT.")
+        )
+        .arg(
+            Arg::with_name("run-timeout")
+            .long("run-timeout")
+            .takes_value(true)
+            .long_help("Seconds the whole run may take. When it is up, repos that are still fetching or not started are reported as timed out, repos already merging or checking out are waited for, and the summary is printed. Defaults to 0, which means no limit. Env var is UPGIT_RUN_TIMEOUT.")
+        )
+        .arg(
+            Arg::with_name("retries")
+            .long("retries")
+            .takes_value(true)
+            .long_help("How many more times a fetch is tried after a network failure that may go away by itself, such as a dropped connection or an HTTP 5xx or 429. Waits grow exponentially between tries. Failed auth, missing repos and host key problems are never retried. Defaults to 2, and 0 turns retrying off. Env var is UPGIT_RETRIES.")
+        )
+        .arg(
+            Arg::with_name("only-failed")
+            .long("only-failed")
+            .takes_value(false)
+            .conflicts_with("resume")
+            .long_help("Only update the repos that did not end up to date or updated in the last run. Repos that were not in the last run are left alone. Env var is UPGIT_ONLY_FAILED set to any value.")
+        )
+        .arg(
+            Arg::with_name("resume")
+            .long("resume")
+            .takes_value(false)
+            .long_help("If the last run was interrupted, only update the repos it did not finish, and include the ones it did in the summary. If it finished, every repo is updated. Env var is UPGIT_RESUME set to any value.")
+        )
+        .arg(
+            Arg::with_name("state-file")
+            .long("state-file")
+            .takes_value(true)
+            .long_help("Where each run's results are saved for --only-failed and --resume. Defaults to $XDG_STATE_HOME/upgit/last-run, or $HOME/.local/state/upgit/last-run. Env var is UPGIT_STATE_FILE.")
+        )
+        .arg(
+            Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .long_help("How the summary is printed. `json` prints one document for the whole run, with each repo's status, branch, remote, old and new HEAD, ahead and behind counts, changed files, duration and error. Progress then goes to stderr. Prompts are still printed to stdout, so use --non-interactive when the output is parsed. Env var is UPGIT_FORMAT.")
+        )
+        .arg(
+            Arg::with_name("fail-on")
+            .long("fail-on")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long_help("Statuses that count as a failure for the exit code, named as in the JSON report, e.g. `--fail-on failed_fetch --fail-on dirty`. Any other status that is not a success then counts as skipped. Exit codes are 0 when every repo is up to date or updated, 2 when some were skipped, 3 when some failed, 1 for a fatal error such as bad configuration, and 130 when interrupted. Env var is comma separated UPGIT_FAIL_ON.")
+        )
+        .arg(
+            Arg::with_name("log-limit")
+            .long("log-limit")
+            .takes_value(true)
+            .long_help("How many incoming commits are listed for each updated repo, newest first, with the short id, author and subject. The rest are counted. Defaults to 10, and 0 lists none. Env var is UPGIT_LOG_LIMIT.")
+        )
+        .arg(
+            Arg::with_name("watch")
+            .long("watch")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long_help("Path pattern that flags a repo under \"Needs attention\" when an update changes a matching file. For example, `--watch Cargo.lock --watch api=migrations/**`. A pattern without a slash matches file names at any depth, one with a slash matches from the repo root, and `*` matches across folders. Prefix with <repo>= to only watch repos whose folder name or path matches. Env var is comma separated UPGIT_WATCH.")
+        )
+        .arg(
+            Arg::with_name("run")
+            .long("run")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long_help("Command to run in a repo after it updated, through `sh -c` with the repo as working directory. Given as <repo>=<command>, e.g. `--run 'api=make' --run '*@Cargo.lock,build.rs=cargo build'`. It only runs in repos whose folder name or path matches <repo>, `*` for every repo. Add @<patterns> to the repo part to only run it when a changed file matches one of the comma separated patterns, as for --watch. Everything after the first `=` is the command, e.g. `*=FOO=1 make`. Commands for a repo run in order and stop at the first failure, which is reported as a failed post-update command. UPGIT_REPO, UPGIT_OLD_HEAD and UPGIT_NEW_HEAD are set for them. Env var is UPGIT_RUN, one command per line.")
+        )
+        .arg(
+            Arg::with_name("run-jobs")
+            .long("run-jobs")
+            .takes_value(true)
+            .long_help("How many --run commands may run at once across all repos. Defaults to 1. Env var is UPGIT_RUN_JOBS.")
+        )
+        .arg(
+            Arg::with_name("rebase")
+            .long("rebase")
+            .takes_value(false)
+            .conflicts_with("no-rebase")
+            .long_help("Rebase local commits onto the remote's instead of merging, whatever pull.rebase and branch.<name>.rebase say. Without this or --no-rebase, each repo's own git config decides, like for `git pull`. Env var is UPGIT_REBASE set to true or false.")
+        )
+        .arg(
+            Arg::with_name("no-rebase")
+            .long("no-rebase")
+            .takes_value(false)
+            .long_help("Merge rather than rebase, whatever pull.rebase and branch.<name>.rebase say.")
+        )
+        .arg(
+            Arg::with_name("ff")
+            .long("ff")
+            .takes_value(false)
+            .conflicts_with_all(&["no-ff", "ff-only"])
+            .long_help("Fast-forward when possible and merge otherwise, whatever pull.ff and merge.ff say. Without this, --no-ff or --ff-only, each repo's own git config decides. Env var is UPGIT_FF set to true, false or only.")
+        )
+        .arg(
+            Arg::with_name("no-ff")
+            .long("no-ff")
+            .takes_value(false)
+            .conflicts_with("ff-only")
+            .long_help("Always make a merge commit, even when a fast-forward is possible.")
+        )
+        .arg(
+            Arg::with_name("ff-only")
+            .long("ff-only")
+            .takes_value(false)
+            .long_help("Only fast-forward. Repos whose branch has diverged from the remote are skipped and reported as not fast-forward.")
+        )
+        .arg(
+            Arg::with_name("prune")
+            .long("prune")
+            .takes_value(false)
+            .conflicts_with("no-prune")
+            .long_help("Remove remote-tracking branches that no longer exist on the remote, whatever fetch.prune and remote.<name>.prune say. Env var is UPGIT_PRUNE set to true or false.")
+        )
+        .arg(
+            Arg::with_name("no-prune")
+            .long("no-prune")
+            .takes_value(false)
+            .long_help("Keep remote-tracking branches that no longer exist on the remote.")
+        )
+        .arg(
+            Arg::with_name("no-hooks")
+            .long("no-hooks")
+            .takes_value(false)
+            .long_help("Do not run the repos' own git hooks. By default, like `git pull`, the post-merge hook runs after a fast-forward or merge, and post-checkout runs after checking out a branch that did not exist locally yet. Hooks are found through core.hooksPath like git does. Env var is UPGIT_NO_HOOKS set to any value.")
+        )
+        .arg(
+            Arg::with_name("non-interactive")
+            .long("non-interactive")
+            .takes_value(false)
+            .long_help("Never prompt, for example when running from cron. Credentials that would need a prompt are skipped, and repos needing them are reported as needing auth. Only ssh keys without a passphrase are used. Env var is UPGIT_NON_INTERACTIVE set to any value.")
         )
         .arg(
             Arg::with_name("git-dirs")
@@ -309,13 +784,32 @@ pub fn new() -> Config {
         )
         .get_matches();
 
+    let non_interactive = get_non_interactive(&matches);
     let config = Config {
-        ssh: get_ssh_keys(&matches),
-        plain: get_plaintexts(&matches),
-        default_ssh: get_default_ssh(&matches),
-        default_plain: get_default_plain(&matches),
-        git_dirs: get_git_dirs(&matches),
+        ssh: get_ssh_keys(&matches, non_interactive),
+        plain: get_plaintexts(&matches, non_interactive),
+        users: get_users(&matches),
+        tokens: get_tokens(&matches),
+        default_ssh: get_default_ssh(&matches, non_interactive),
+        default_plain: get_default_plain(&matches, non_interactive),
+        git_dirs: get_git_dirs(&matches, non_interactive),
         share: get_share(&matches),
+        host_key_check: get_host_key_check(&matches),
+        non_interactive,
+        jobs: get_jobs(&matches),
+        host_jobs: get_host_jobs(&matches),
+        fetch_timeout: get_timeout(&matches, "timeout", "UPGIT_TIMEOUT", 300),
+        run_timeout: get_timeout(&matches, "run-timeout", "UPGIT_RUN_TIMEOUT", 0),
+        retries: get_retries(&matches),
+        log_limit: get_log_limit(&matches),
+        watch: get_watch(&matches),
+        post_update: get_post_update(&matches),
+        hooks: get_hooks(&matches),
+        pull: get_pull(&matches),
+        rerun: get_rerun(&matches),
+        state_file: get_state_file(&matches),
+        format: get_format(&matches),
+        fail_on: get_fail_on(&matches),
     };
 
     config
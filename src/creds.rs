@@ -1,17 +1,22 @@
 use git2::{Cred, CredentialType};
+use std::io::{self, Write};
 use std::path::Path;
 use rpassword;
 use std::collections::{HashMap, HashSet};
 use crate::config;
-use std::env;
 use text_io::read;
 use url;
 use std::fmt;
 use crate::string_ops;
+use crate::keys;
+use crate::broker;
+use crate::tokens;
+use crate::url_rewrite;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum GitCred {
+pub enum GitCred {
     Plain(String), // PW for that user/url combo
+    Token(String), // personal access token, only for its own host
     Ssh(String, Option<String>), // path to ssh key, optional passphrase
 }
 
@@ -32,16 +37,30 @@ type Repo = HashMap<String, RepoCred>; // repo_path -> RepoCred
 pub struct Storage {
     default_ssh: Option<GitCred>, // code ensures its an ssh
     default_plain: Option<GitCred>, // code ensures its a plaintext
+    tokens: tokens::Tokens,
     repo_graph: RepoGraph,
     keys: HashSet<GitCred>, // code ensures it is only ssh keys
     share: config::Share,
+    interactive: bool,
+    clones: Clones,
+    usernames: Usernames,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct GitUrl {
+type Clones = HashMap<String, HashSet<GitUrl>>; // identity -> urls it was reached through
+
+// Usernames for https remotes that do not name one.
+#[derive(Debug, Clone, Default)]
+struct Usernames {
+    configured: HashMap<String, String>, // host -> username, from --user
+    resolved: HashMap<String, String>, // sharing key -> username
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GitUrl {
     domain: String,
     org: String,
     repo: String,
+    port: Option<u16>, // only when not the scheme's default
     scheme: String,
     username: String,
 }
@@ -76,76 +95,194 @@ impl std::error::Error for UpgitErr {
     }
 }
 
-fn parse_git_url(input_url: String) -> Result<GitUrl, Box<dyn std::error::Error>> {
-    // e.g. ssh.
-    let parts: Vec<_> = input_url.split('@').collect();
-    if parts.len() < 1 {
-        return Err(mk_err("missing user"));
-    }
-    if parts.len() < 2 {
-        return Err(mk_err("did not have other info"));
-    }
-    let domain_parts: Vec<_> = parts[1].split(':').collect();
-    if domain_parts.len() < 1 {
-        return Err(mk_err("did not have domain"));
+// A url's path as org and repo, with `.` and `..` resolved. The repo drops a
+// trailing `.git`, so both spellings of a remote group together.
+fn split_path(path: &str) -> (String, String) {
+    let mut segments: Vec<&str> = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop();
+            },
+            x => segments.push(x),
+        }
     }
-    if domain_parts.len() < 2 {
-        return Err(mk_err("did not have other info"));
+    match segments.split_last() {
+        Some((repo, org)) => (org.join("/"), repo.strip_suffix(".git").unwrap_or(repo).to_string()),
+        None => (String::from(""), String::from("")),
     }
-    let org_domain_parts: Vec<_> = domain_parts[1].split('/').collect();
-    let (org, repo) = match org_domain_parts.len() {
-        0 => (String::from(""), String::from("")),
-        1 => (String::from(""), org_domain_parts[0].to_string()),
-        2 => (org_domain_parts[0].to_string(), org_domain_parts[1].to_string()),
-        x => (org_domain_parts[0..(x - 2)].to_vec().join("/"), org_domain_parts[x - 1].to_string()),
+}
+
+// The port is left out when it is the scheme's default anyway.
+fn non_default_port(scheme: &str, port: Option<u16>) -> Option<u16> {
+    let default = match scheme {
+        "ssh" => Some(22),
+        "git" => Some(9418),
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
     };
+    port.filter(|x| Some(*x) != default)
+}
 
-    return Ok(GitUrl{
-        domain: domain_parts[0].to_string(),
-        org,
-        repo,
-        scheme: String::from("git"),
-        username: parts[0].to_string(),
-    });
+fn is_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    chars.next().is_some_and(|x| x.is_ascii_alphabetic())
+        && chars.all(|x| x.is_ascii_alphanumeric() || x == '+' || x == '-' || x == '.')
 }
 
-fn parse_url(input_url: String) -> Result<GitUrl, Box<dyn std::error::Error>> {
-    let mut with_protocol = input_url.clone();
-    if with_protocol.starts_with(&String::from("git@")) {
-        return parse_git_url(input_url);
-    }
-
-    if !with_protocol.starts_with(&String::from("https://")) &&
-        !with_protocol.starts_with(&String::from("http://"))
-    {
-        with_protocol = String::from("unknown://") + &with_protocol;
-    }
-    let url = url::Url::parse(&with_protocol)?;
-    let segments = url.path_segments().map(|c| c.collect::<Vec<_>>()).unwrap_or(vec![]);
-    let mut org = String::from("");
-    let mut repo = String::from("");
-    let num_segments = segments.len();
-    if num_segments == 1 {
-        repo = segments[0].to_string();
-    } else if num_segments > 1 {
-        org = segments[0..(num_segments - 1)].to_vec().join("/");
-        repo = segments[num_segments - 1].to_string();
-    }
+// Windows paths like C:\repo or C:/repo look like scp-like urls with a one
+// letter host, which git does not take them for either.
+fn is_drive_letter(host: &str) -> bool {
+    host.len() == 1 && host.chars().all(|x| x.is_ascii_alphabetic())
+}
+
+// scheme://[user@]host[:port]/path, with ssh also spelled git+ssh or ssh+git.
+fn parse_scheme_url(input_url: &str) -> Result<GitUrl, Box<dyn std::error::Error>> {
+    let url = url::Url::parse(input_url)?;
+    let scheme = match url.scheme() {
+        "git+ssh" | "ssh+git" => "ssh",
+        x => x,
+    };
+    let (org, repo) = split_path(url.path());
     Ok(GitUrl{
-        domain: url.host_str().unwrap_or(&String::from("")).to_string(),
+        domain: url.host_str().unwrap_or("").to_lowercase(),
         org,
         repo,
-        scheme: url.scheme().to_string(),
+        port: non_default_port(scheme, url.port()),
+        scheme: scheme.to_string(),
         username: url.username().to_string(),
     })
 }
 
+// [user@]host:path, where the host may be bracketed as [host:port] or as an
+// IPv6 address. A slash before the first colon makes it a local path instead.
+fn parse_scp_url(input_url: &str) -> Option<GitUrl> {
+    let (mut username, rest) = match input_url.split_once('@') {
+        Some((user, rest)) if !user.contains(':') && !user.contains('/') && !user.starts_with('[') => (user, rest),
+        _ => ("", input_url),
+    };
+    let (host, port, path) = if let Some(bracketed) = rest.strip_prefix('[') {
+        let (inside, path) = bracketed.split_once(']')?;
+        let path = path.strip_prefix(':')?;
+        let inside = match inside.split_once('@') {
+            Some((user, host)) if username.is_empty() => {
+                username = user;
+                host
+            },
+            _ => inside,
+        };
+        match inside.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host.to_string(), Some(port.parse::<u16>().ok()?), path),
+            _ => (format!("[{}]", inside), None, path),
+        }
+    } else {
+        let (host, path) = rest.split_once(':')?;
+        if host.is_empty() || host.contains('/') || is_drive_letter(host) {
+            return None;
+        }
+        (host.to_string(), None, path)
+    };
+    let (org, repo) = split_path(path);
+    Some(GitUrl{
+        domain: host.to_lowercase(),
+        org,
+        repo,
+        port: non_default_port("ssh", port),
+        scheme: String::from("ssh"),
+        username: username.to_string(),
+    })
+}
+
+fn is_local_path(input_url: &str) -> bool {
+    let mut chars = input_url.chars();
+    let drive = matches!((chars.next(), chars.next()), (Some(letter), Some(':')) if letter.is_ascii_alphabetic());
+    drive
+        || input_url.starts_with('/')
+        || input_url.starts_with('.')
+        || input_url.starts_with('~')
+        || input_url.starts_with('\\')
+}
+
+// Every form git takes for a remote, as listed under GIT URLS in git-fetch(1).
+// Anything else is parsed as well as it can be, so at least copies of the
+// same remote still group together.
+fn parse_url(input_url: String) -> Result<GitUrl, Box<dyn std::error::Error>> {
+    if let Some((scheme, _)) = input_url.split_once("://") {
+        if is_scheme(scheme) {
+            return parse_scheme_url(&input_url);
+        }
+    }
+    if let Some(git_url) = parse_scp_url(&input_url) {
+        return Ok(git_url);
+    }
+    if is_local_path(&input_url) {
+        let (org, repo) = split_path(&input_url.replace('\\', "/"));
+        return Ok(GitUrl{
+            domain: String::from(""),
+            org,
+            repo,
+            port: None,
+            scheme: String::from("file"),
+            username: String::from(""),
+        });
+    }
+    if input_url.is_empty() {
+        return Err(mk_err("empty url"));
+    }
+    parse_scheme_url(&format!("unknown://{}", input_url))
+}
+
+fn git_url_identity(git_url: &GitUrl) -> String {
+    let path: Vec<&str> = vec![git_url.org.as_str(), git_url.repo.as_str()].into_iter().filter(|x| !x.is_empty()).collect();
+    format!("{}/{}", git_url.domain, path.join("/"))
+}
+
+// What a remote is, whichever way it is reached: its host and path, without
+// scheme, user, port or `.git`. An https and an ssh clone of a repo share it.
+pub fn identity(url: &str) -> Option<String> {
+    parse_url(url.to_string()).ok().map(|x| git_url_identity(&x))
+}
+
+pub fn remote_domain(url: &str) -> Option<String> {
+    parse_url(url.to_string()).ok().map(|x| x.domain.to_lowercase())
+}
+
 fn get_shared_pwd_repo(repo: &Repo, repo_path: &String, seen: &Seen) -> Option<GitCred> {
     repo.iter()
         .find(|(k, v)| *k != repo_path && !seen.contains(&v.active))
         .map(|(_, v)| v.active.clone())
 }
 
+fn kind_matches(cred: &GitCred, is_ssh: bool) -> bool {
+    matches!(cred, GitCred::Ssh(..)) == is_ssh
+}
+
+// Whether the scheme keeps credentials from being read on the wire.
+fn is_encrypted(scheme: &str) -> bool {
+    matches!(scheme, "https" | "ssh" | "file")
+}
+
+// A credential used for one clone may go to another only over a transport at
+// least as safe, and for the same user unless the other url names none.
+fn may_share_between(from: &GitUrl, to: &GitUrl) -> bool {
+    (is_encrypted(&to.scheme) || !is_encrypted(&from.scheme))
+        && (to.username.is_empty() || to.username == from.username)
+}
+
+// Copies of the repo cloned through other urls. Only creds of the kind asked
+// for are any use, a password does not work for ssh.
+fn get_shared_pwd_clones(rg: &RepoGraph, clones: &Clones, git_url: &GitUrl, repo_path: &String, seen: &Seen, is_ssh: bool) -> Option<GitCred> {
+    let urls = clones.get(&git_url_identity(git_url))?;
+    urls.iter()
+        .filter(|x| may_share_between(x, git_url))
+        .filter_map(|x| rg.get(&url_to_domain(x))?.get(&git_url.org)?.get(&git_url.repo))
+        .flat_map(|repo| repo.iter())
+        .find(|(k, v)| *k != repo_path && !seen.contains(&v.active) && kind_matches(&v.active, is_ssh))
+        .map(|(_, v)| v.active.clone())
+}
+
 fn get_shared_pwd_org(org: &Org, repo_path: &String, seen: &Seen) -> Option<GitCred> {
     org.iter().find_map(|(_, v)| get_shared_pwd_repo(v, &repo_path, seen))
 }
@@ -155,23 +292,32 @@ fn get_shared_pwd_domain(domain: &Domain, repo_path: &String, seen: &Seen) -> Op
 }
 
 // Looks within the tree for an existing, that isn't self
-fn get_shared_cred(rg: &RepoGraph, share: &config::Share, git_url: GitUrl, repo_path: &String, seen: &Seen) -> Option<GitCred> {
+fn get_shared_cred(
+    rg: &RepoGraph,
+    clones: &Clones,
+    share: &config::Share,
+    git_url: GitUrl,
+    repo_path: &String,
+    seen: &Seen,
+    is_ssh: bool,
+) -> Option<GitCred> {
     // Need to pass in seen because of how updating seen works currently.
     if share == &config::Share::Never || share == &config::Share::Defaults {
         return None
     };
 
     let domain_key = url_to_domain(&git_url);
+    if share >= &config::Share::Duplicate {
+        let same_domain = rg.get(&domain_key)
+            .and_then(|domain| domain.get(&git_url.org))
+            .and_then(|org| org.get(&git_url.repo))
+            .and_then(|repo| get_shared_pwd_repo(repo, repo_path, seen));
+        if let Some(cred) = same_domain.or_else(|| get_shared_pwd_clones(rg, clones, &git_url, repo_path, seen, is_ssh)) {
+            return Some(cred);
+        }
+    }
     if let Some(domain) = rg.get(&domain_key) {
         if let Some(org) = domain.get(&git_url.org) {
-            if let Some(repo) = org.get(&git_url.repo) {
-                if share >= &config::Share::Duplicate {
-                    if let Some(cred) = get_shared_pwd_repo(repo, repo_path, &seen) {
-                        return Some(cred);
-                    }
-                }
-            }
-
             if share >= &config::Share::Org {
                 if let Some(cred) = get_shared_pwd_org(org, repo_path, &seen) {
                     return Some(cred);
@@ -189,20 +335,48 @@ fn get_shared_cred(rg: &RepoGraph, share: &config::Share, git_url: GitUrl, repo_
     None
 }
 
-fn prompt_cred(url: String, is_ssh: bool, keys: Vec<&GitCred>) -> GitCred {
-    if is_ssh { prompt_ssh(url, keys) } else { prompt_plaintext(url) }
+fn usable_without_prompt(cred: &GitCred) -> bool {
+    match cred {
+        GitCred::Ssh(path, None) => !keys::needs_passphrase(path),
+        _ => true,
+    }
+}
+
+// None means a prompt was needed, but the user can not be asked.
+fn prompt_cred(url: String, is_ssh: bool, keys: Vec<&GitCred>, interactive: bool) -> Option<GitCred> {
+    if !interactive {
+        return if is_ssh {
+            keys.into_iter().find(|x| usable_without_prompt(x)).cloned()
+        } else {
+            None
+        };
+    }
+    Some(if is_ssh { prompt_ssh(url, keys) } else { prompt_plaintext(url) })
 }
 
 fn prompt_ssh(url: String, keys: Vec<&GitCred>) -> GitCred {
     if keys.len() > 0 {
-        return pick(&keys);
+        return unlock(pick(&keys), &url);
     }
-    let ssh_path = format!("{}/.ssh/id_rsa", env::var("HOME").expect("No env var HOME present"));
+    let ssh_path = keys::default_key_path();
     println!("\nNo verified, untried ssh keys found. Please enter password for ssh key assumed to exist at {}, for \"{}\":", &ssh_path, &url);
     let ssh_pass = config::prompt_ssh_pass(&ssh_path);
     GitCred::Ssh(ssh_path, string_ops::str_to_opt(ssh_pass))
 }
 
+// Discovered keys are stored without a passphrase until they are picked.
+// Only encrypted ones need the user to supply it.
+fn unlock(cred: GitCred, url: &str) -> GitCred {
+    match cred {
+        GitCred::Ssh(path, None) if keys::needs_passphrase(&path) => {
+            println!("\nSsh key {} is encrypted, for \"{}\":", &path, url);
+            let ssh_pass = config::prompt_ssh_pass(&path);
+            GitCred::Ssh(path, string_ops::str_to_opt(ssh_pass))
+        },
+        x => x,
+    }
+}
+
 fn prompt_plaintext(url: String) -> GitCred {
     let input_msg = format!("Password:");
     let confirm_msg = format!("Confirm:");
@@ -220,6 +394,98 @@ fn prompt_plaintext(url: String) -> GitCred {
     GitCred::Plain(new_pass)
 }
 
+// Keys a resolved username is shared under, most specific first, as far as
+// the share level allows. Like creds, defaults and below share nothing.
+fn username_keys(share: &config::Share, git_url: &GitUrl) -> Vec<String> {
+    let domain_key = url_to_domain(git_url);
+    let mut keys = vec![];
+    if share >= &config::Share::Duplicate {
+        keys.push(format!("repo:{}", git_url_identity(git_url)));
+    }
+    if share >= &config::Share::Org {
+        keys.push(format!("org:{}/{}", domain_key, git_url.org));
+    }
+    if share >= &config::Share::Domain {
+        keys.push(format!("domain:{}", domain_key));
+    }
+    keys
+}
+
+// What repos must have in common to be handed the credential answered for
+// another repo without asking themselves, as far as the share level allows.
+// Always the same transport and user. Defaults and below hand out nothing.
+pub fn answer_key(share: &config::Share, git_url: &GitUrl) -> Option<String> {
+    let domain_key = url_to_domain(git_url);
+    match share {
+        config::Share::Domain => Some(domain_key),
+        config::Share::Org => Some(format!("{}/{}", domain_key, git_url.org)),
+        config::Share::Duplicate => Some(format!("{}/{}/{}", domain_key, git_url.org, git_url.repo)),
+        config::Share::Defaults | config::Share::Never => None,
+    }
+}
+
+// The repo's config, which includes the global one.
+fn git_config(repo_path: &str) -> Option<git2::Config> {
+    git2::Repository::open(repo_path)
+        .and_then(|x| x.config())
+        .or_else(|_| git2::Config::open_default())
+        .ok()
+}
+
+// Whether credential.<pattern>.* applies to a url: the same scheme, host and
+// port, the same user if the pattern has one, and a path that is a prefix.
+fn credential_url_matches(pattern: &url::Url, url: &url::Url) -> bool {
+    let path = pattern.path().trim_end_matches('/');
+    pattern.scheme() == url.scheme()
+        && pattern.host_str() == url.host_str()
+        && pattern.port_or_known_default() == url.port_or_known_default()
+        && (pattern.username().is_empty() || pattern.username() == url.username())
+        && (path.is_empty() || url.path() == path || url.path().starts_with(&format!("{}/", path)))
+}
+
+// git's credential.<url>.username, where the most specific matching url wins,
+// then credential.username. libgit2's helper only knows exact urls and bare
+// hosts without a port.
+fn config_username(config: &git2::Config, url: &str) -> Option<String> {
+    let parsed = url::Url::parse(url).ok()?;
+    let mut best: Option<(usize, String)> = None;
+    if let Ok(entries) = config.entries(Some("credential\\..*\\.username")) {
+        let _ = entries.for_each(|entry| {
+            let (name, value) = match (entry.name(), entry.value()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return,
+            };
+            let pattern = name.strip_prefix("credential.")
+                .and_then(|x| x.strip_suffix(".username"))
+                .and_then(|x| url::Url::parse(x).ok());
+            if let Some(pattern) = pattern.filter(|x| credential_url_matches(x, &parsed)) {
+                // On a tie the later one wins, as in git.
+                let specificity = pattern.path().trim_end_matches('/').len();
+                if best.as_ref().is_none_or(|(x, _)| specificity >= *x) {
+                    best = Some((specificity, value.to_string()));
+                }
+            }
+        });
+    }
+    best.map(|(_, username)| username).or_else(|| config.get_string("credential.username").ok())
+}
+
+// A username from the credential helpers git would ask.
+fn helper_username(config: &git2::Config, url: &str) -> Option<String> {
+    git2::CredentialHelper::new(url).config(config).execute().map(|(username, _)| username)
+}
+
+fn prompt_username(url: &str, interactive: bool) -> Option<String> {
+    if !interactive {
+        return None;
+    }
+    println!("\nPlease enter username for upgitting \"{}\":", url);
+    print!("Username (blank to skip): ");
+    let _ = io::stdout().flush();
+    let username: String = read!("{}\n");
+    string_ops::str_to_opt(username.trim().to_string())
+}
+
 impl Storage {
     pub fn from_config(config: &config::Config) -> Storage {
         let mut storage = Storage {
@@ -237,12 +503,23 @@ impl Storage {
                 Some(x) => Some(GitCred::Plain(x.clone())),
                 _ => None
             },
+            tokens: config.tokens.clone(),
             share: config.share.clone(),
             repo_graph: HashMap::new(),
+            interactive: !config.non_interactive,
+            clones: HashMap::new(),
+            usernames: Usernames {
+                configured: config.users.clone(),
+                resolved: HashMap::new(),
+            },
         };
 
+        // Remotes are matched by their rewritten urls, so given ones are rewritten too.
+        let rewrites = git2::Config::open_default()
+            .map(|x| url_rewrite::Rewrites::from_config(&x))
+            .unwrap_or_default();
         for (k, v) in config.plain.iter() {
-            if let Ok(git_url) = parse_url(k.to_string()) {
+            if let Ok(git_url) = parse_url(rewrites.fetch_url(k)) {
                 storage.ensure_repo_node(&git_url);
                 let domain_key = url_to_domain(&git_url);
                 if let Some(domain) = storage.repo_graph.get_mut(&domain_key) {
@@ -265,13 +542,34 @@ impl Storage {
             storage.keys.insert(GitCred::Ssh(k.to_string(), string_ops::str_to_opt(v.to_string())));
         }
 
+        // Standard keys in ~/.ssh are candidates too, unless already provided above.
+        let provided: HashSet<String> = storage.keys.iter()
+            .chain(storage.default_ssh.iter())
+            .filter_map(|x| match x {
+                GitCred::Ssh(path, _) => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        for path in keys::discover() {
+            if !provided.contains(&path) {
+                storage.keys.insert(GitCred::Ssh(path, None));
+            }
+        }
+
         storage
     }
 
-    fn get_cred(&mut self, git_url: GitUrl, repo_path: String, is_ssh: bool, url: String) -> GitCred {
+    pub fn get_cred(&mut self, git_url: GitUrl, repo_path: String, is_ssh: bool, url: String) -> Option<GitCred> {
         self.ensure_repo_node(&git_url);
         let domain_key = url_to_domain(&git_url);
         let rg_clone = self.repo_graph.clone();
+        let clones = self.clones.clone();
+        // A host's token goes before anything else that might work for it, but
+        // only over https, it must not be sent in the clear.
+        let token = match git_url.scheme.as_str() {
+            "https" if !is_ssh => self.tokens.get(&git_url.domain).map(|x| GitCred::Token(x.clone())),
+            _ => None,
+        };
         if let Some(domain) = self.repo_graph.get_mut(&domain_key) {
             if let Some(org) = domain.get_mut(&git_url.org) {
                 if let Some(repo) = org.get_mut(&git_url.repo) {
@@ -281,36 +579,47 @@ impl Storage {
                             // and failed. So we need to get a new one. And add the old one
                             // to the set of seen creds.
                             pathed_repo.seen.insert(pathed_repo.active.clone());
-                            if let Some(shared_cred) = get_shared_cred(&rg_clone, &self.share, git_url, &repo_path, &pathed_repo.seen) {
+                            if let Some(token) = token.filter(|x| !pathed_repo.seen.contains(x)) {
+                                pathed_repo.active = token.clone();
+                                return Some(token);
+                            }
+                            if let Some(shared_cred) = get_shared_cred(&rg_clone, &clones, &self.share, git_url, &repo_path, &pathed_repo.seen, is_ssh) {
                                 let cred_clone = shared_cred.clone();
                                 pathed_repo.active = shared_cred;
-                                return cred_clone;
+                                return Some(cred_clone);
                             } else {
                                 if let Some(default_cred) = if is_ssh { self.default_ssh.clone() } else { self.default_plain.clone() } {
                                     if !pathed_repo.seen.contains(&default_cred) {
                                         let cred_clone = default_cred.clone();
                                         pathed_repo.active = default_cred;
-                                        return cred_clone;
+                                        return Some(cred_clone);
                                     }
                                 }
                                 let untried_keys: Vec<_> = self.keys.difference(&pathed_repo.seen).collect();
-                                let cred = prompt_cred(url, is_ssh, untried_keys);
+                                let cred = prompt_cred(url, is_ssh, untried_keys, self.interactive)?;
+                                remember_unlocked(&mut self.keys, &cred);
                                 let cred_clone = cred.clone();
                                 if pathed_repo.seen.contains(&cred) {
                                     println!("You already tried this cred, but trying again anyways.");
                                 }
                                 pathed_repo.active = cred;
-                                return cred_clone;
+                                return Some(cred_clone);
                             }
                         },
                         None => {
-                            let active = match get_shared_cred(&rg_clone, &self.share, git_url, &repo_path, &HashSet::new()) {
+                            let found = match token {
+                                Some(token) => Some(token),
+                                None => get_shared_cred(&rg_clone, &clones, &self.share, git_url, &repo_path, &HashSet::new(), is_ssh),
+                            };
+                            let active = match found {
                                 Some(cred) => cred,
                                 None => {
                                     if let Some(default_cred) = if is_ssh { self.default_ssh.clone() } else { self.default_plain.clone() } {
                                         default_cred.clone()
                                     } else {
-                                        prompt_cred(url, is_ssh, self.keys.iter().collect())
+                                        let cred = prompt_cred(url, is_ssh, self.keys.iter().collect(), self.interactive)?;
+                                        remember_unlocked(&mut self.keys, &cred);
+                                        cred
                                     }
                                 },
                             };
@@ -319,7 +628,7 @@ impl Storage {
                                 repo_path,
                                 RepoCred { active, seen: HashSet::new() },
                             );
-                            return active_clone;
+                            return Some(active_clone);
                         },
                     };
                 };
@@ -328,8 +637,66 @@ impl Storage {
         panic!("This should never be reached");
     }
 
+    // The username for a remote whose url has none. Retries keep the one first
+    // found for the repo path, so only the password changes between attempts.
+    // None means only a prompt could tell, and the user can not be asked.
+    pub fn get_username(&mut self, git_url: &GitUrl, repo_path: &str, url: &str) -> Option<String> {
+        let path_key = format!("path:{}", repo_path);
+        if let Some(username) = self.usernames.resolved.get(&path_key) {
+            return Some(username.clone());
+        }
+        let keys = username_keys(&self.share, git_url);
+        let config = git_config(repo_path);
+        let username = self.usernames.configured.get(&git_url.domain).cloned()
+            .or_else(|| config.as_ref().and_then(|x| config_username(x, url)))
+            .or_else(|| keys.iter().find_map(|x| self.usernames.resolved.get(x)).cloned())
+            .or_else(|| config.as_ref().and_then(|x| helper_username(x, url)))
+            .or_else(|| prompt_username(url, self.interactive))?;
+        for key in keys {
+            self.usernames.resolved.entry(key).or_insert_with(|| username.clone());
+        }
+        self.usernames.resolved.insert(path_key, username.clone());
+        Some(username)
+    }
+
+    pub fn share(&self) -> &config::Share {
+        &self.share
+    }
+
+    // The credential last handed out for this repo path, still unrejected. A
+    // fetch retried after a network error starts with it again, only an auth
+    // failure moves on to the next one.
+    pub fn reuse_active(&self, git_url: &GitUrl, repo_path: &String) -> Option<GitCred> {
+        self.repo_graph.get(&url_to_domain(git_url))
+            .and_then(|domain| domain.get(&git_url.org))
+            .and_then(|org| org.get(&git_url.repo))
+            .and_then(|repo| repo.get(repo_path))
+            .map(|x| x.active.clone())
+    }
+
+    // Whether a credential was already handed out for this repo path.
+    pub fn has_cred(&self, git_url: &GitUrl, repo_path: &String) -> bool {
+        self.repo_graph.get(&url_to_domain(git_url))
+            .and_then(|domain| domain.get(&git_url.org))
+            .and_then(|org| org.get(&git_url.repo))
+            .map(|repo| repo.contains_key(repo_path))
+            .unwrap_or(false)
+    }
+
+    // Records a credential resolved for another repo as this repo's first attempt.
+    pub fn assign(&mut self, git_url: &GitUrl, repo_path: String, cred: GitCred) {
+        self.ensure_repo_node(git_url);
+        if let Some(repo) = self.repo_graph.get_mut(&url_to_domain(git_url))
+            .and_then(|domain| domain.get_mut(&git_url.org))
+            .and_then(|org| org.get_mut(&git_url.repo))
+        {
+            repo.insert(repo_path, RepoCred { active: cred, seen: HashSet::new() });
+        }
+    }
+
     fn ensure_repo_node(&mut self, git_url: &GitUrl) {
         let domain_key = url_to_domain(git_url);
+        self.clones.entry(git_url_identity(git_url)).or_default().insert(git_url.clone());
         match self.repo_graph.get_mut(&domain_key) {
             Some(domain) => {
                 match domain.get_mut(&git_url.org) {
@@ -358,11 +725,22 @@ impl Storage {
     }
 }
 
-fn url_to_domain(git_url: &GitUrl) -> String {
-    format!("{}://{}@{}", git_url.scheme, git_url.username, git_url.domain)
+// Once a discovered key has been unlocked, later picks should reuse the passphrase.
+fn remember_unlocked(keys: &mut HashSet<GitCred>, cred: &GitCred) {
+    if let GitCred::Ssh(path, Some(_)) = cred {
+        let candidate = GitCred::Ssh(path.clone(), None);
+        if keys.remove(&candidate) {
+            keys.insert(cred.clone());
+        }
+    }
 }
 
-type SharedData = std::sync::Arc<std::sync::Mutex<Storage>>;
+pub fn url_to_domain(git_url: &GitUrl) -> String {
+    match git_url.port {
+        Some(port) => format!("{}://{}@{}:{}", git_url.scheme, git_url.username, git_url.domain, port),
+        None => format!("{}://{}@{}", git_url.scheme, git_url.username, git_url.domain),
+    }
+}
 
 fn git_cred_to_cred(username: String, cred: GitCred) -> Result<Cred, git2::Error> {
     match cred {
@@ -374,7 +752,7 @@ fn git_cred_to_cred(username: String, cred: GitCred) -> Result<Cred, git2::Error
                 key_pass.as_deref(),
             )
         },
-        GitCred::Plain(pass) => Cred::userpass_plaintext(&username, &pass)
+        GitCred::Plain(pass) | GitCred::Token(pass) => Cred::userpass_plaintext(&username, &pass)
     }
 }
 
@@ -389,15 +767,16 @@ fn pick(choices: &Vec<&GitCred>) -> GitCred {
     let mut i = 1;
     println!("\nPick one of the following:");
     for x in choices.iter() {
-        if let GitCred::Ssh(x, _) = x {
-            println!("{}) {:?}", i, x);
+        if let GitCred::Ssh(path, pass) = x {
+            let needs_pass = pass.is_none() && keys::needs_passphrase(path);
+            println!("{}) {:?}{}", i, path, if needs_pass { " (passphrase required)" } else { "" });
         }
         i += 1;
     }
     let choice: String = read!("{}\n");
     if let Ok(num) = choice.parse::<usize>() {
-        if num <= num_choices {
-            return choices[num].clone();
+        if num >= 1 && num <= num_choices {
+            return choices[num - 1].clone();
         };
     };
 
@@ -405,27 +784,50 @@ fn pick(choices: &Vec<&GitCred>) -> GitCred {
     pick(choices)
 }
 
-pub fn callback(url: &str, username_from_url: Option<&str>, allowed_types: CredentialType, shared_data: SharedData, repo_path: &String) -> Result<Cred, git2::Error> {
+fn auth_required_error(url: &str) -> git2::Error {
+    git2::Error::new(
+        git2::ErrorCode::Auth,
+        git2::ErrorClass::Callback,
+        format!("Credentials for \"{}\" need a prompt, but upgit is running non-interactively", url),
+    )
+}
+
+pub fn is_auth_required(err: &git2::Error) -> bool {
+    err.code() == git2::ErrorCode::Auth && err.class() == git2::ErrorClass::Callback
+}
+
+// `first` is the first call of a fetch attempt. Later calls in the same
+// attempt mean libgit2 was refused the previous credential.
+pub fn callback(url: &str, username_from_url: Option<&str>, allowed_types: CredentialType, broker: &broker::Broker, repo_path: &str, first: bool) -> Result<Cred, git2::Error> {
     if allowed_types.is_ssh_key() {
         let user = username_from_url.unwrap_or("git");
-        let mut shared_data = shared_data.lock().expect("could not acquire lock");
-        let new_cred = shared_data.get_cred(
+        let new_cred = broker.request(
             parse_url(String::from(url)).expect(format!("Expected url \"{}\" to parse :(", url).as_str()),
-            repo_path.to_string(),
+            url,
+            repo_path,
             true,
-            url.to_string(),
-        );
+            first,
+        ).ok_or_else(|| auth_required_error(url))?;
         git_cred_to_cred(user.to_string(), new_cred)
     } else if  allowed_types.is_user_pass_plaintext() {
-        let user = username_from_url.expect("no username available in git url");
-        let mut shared_data = shared_data.lock().expect("unable to acquire lock");
-        let new_cred = shared_data.get_cred(
-            parse_url(String::from(url)).expect("Expected url to parse :("),
-            repo_path.to_string(),
+        let git_url = parse_url(String::from(url)).expect("Expected url to parse :(");
+        let new_cred = broker.request(
+            git_url.clone(),
+            url,
+            repo_path,
             false,
-            url.to_string(),
-        );
-        git_cred_to_cred(user.to_string(), new_cred)
+            first,
+        ).ok_or_else(|| auth_required_error(url))?;
+        // A user in the url wins, then the one the forge expects with a token.
+        let forge_user = match &new_cred {
+            GitCred::Token(_) => tokens::forge_username(&git_url.domain),
+            _ => None,
+        };
+        let user = match username_from_url.or(forge_user) {
+            Some(user) => user.to_string(),
+            None => broker.username(git_url, url, repo_path).ok_or_else(|| auth_required_error(url))?,
+        };
+        git_cred_to_cred(user, new_cred)
     } else {
         Err(git2::Error::from_str("Unable to select a credential type, only plaintext or ssh key are supported at this time."))
     }
@@ -440,6 +842,7 @@ mod tests {
                 domain: String::from(domain),
                 org: String::from(org),
                 repo: String::from(repo),
+                port: None,
                 scheme: String::from(scheme),
                 username: String::from(username),
             }
@@ -469,7 +872,7 @@ mod tests {
         #[test]
         fn orgless_repo() {
             let input_url = String::from("https://gitstub.io/repo-sans-org.git");
-            let expected = mk_url("gitstub.io", "", "repo-sans-org.git", "https", "");
+            let expected = mk_url("gitstub.io", "", "repo-sans-org", "https", "");
             assert_eq!(parse_url(input_url).unwrap(), expected);
         }
 
@@ -483,23 +886,340 @@ mod tests {
         #[test]
         fn short_orgs() {
             let input_url = String::from("https://github.com/org/repo.git");
-            let expected = mk_url("github.com", "org", "repo.git", "https", "");
+            let expected = mk_url("github.com", "org", "repo", "https", "");
             assert_eq!(parse_url(input_url).unwrap(), expected);
         }
 
         #[test]
         fn long_orgs() {
             let input_url = String::from("https://gitstub.io/my/long/org/repo.git");
-            let expected = mk_url("gitstub.io", "my/long/org", "repo.git", "https", "");
+            let expected = mk_url("gitstub.io", "my/long/org", "repo", "https", "");
             assert_eq!(parse_url(input_url).unwrap(), expected);
         }
 
         #[test]
         fn git_ssh_urls() {
             let input_url = String::from("git@gitstub.io/my/long/org/repo.git");
-            let expected = mk_url("gitstub.io", "my/long/org", "repo.git", "unknown", "git");
+            let expected = mk_url("gitstub.io", "my/long/org", "repo", "unknown", "git");
             assert_eq!(parse_url(input_url).unwrap(), expected);
         }
+
+        #[test]
+        fn scp_like_urls() {
+            let expected = mk_url("github.com", "org", "repo", "ssh", "git");
+            assert_eq!(parse_url(String::from("git@github.com:org/repo.git")).unwrap(), expected);
+            let expected = mk_url("git.corp", "org", "repo", "ssh", "deploy");
+            assert_eq!(parse_url(String::from("deploy@git.corp:org/repo")).unwrap(), expected);
+            let expected = mk_url("git.corp", "", "repo", "ssh", "");
+            assert_eq!(parse_url(String::from("git.corp:repo.git/")).unwrap(), expected);
+        }
+
+        #[test]
+        fn ssh_urls_with_ports() {
+            let expected = GitUrl { port: Some(2222), ..mk_url("host", "org", "repo", "ssh", "user") };
+            assert_eq!(parse_url(String::from("ssh://user@host:2222/org/repo.git")).unwrap(), expected);
+            assert_eq!(parse_url(String::from("user@[host:2222]:org/repo.git")).unwrap(), expected);
+            assert_eq!(parse_url(String::from("[user@host:2222]:org/repo.git")).unwrap(), expected);
+            // Default ports are left out, so they group with urls that have none.
+            let expected = mk_url("host", "org", "repo", "ssh", "git");
+            assert_eq!(parse_url(String::from("ssh://git@host:22/org/repo.git")).unwrap(), expected);
+            assert_eq!(parse_url(String::from("git+ssh://git@host/org/repo.git")).unwrap(), expected);
+        }
+
+        #[test]
+        fn ipv6_hosts() {
+            let expected = GitUrl { port: Some(2222), ..mk_url("[::1]", "org", "repo", "ssh", "git") };
+            assert_eq!(parse_url(String::from("ssh://git@[::1]:2222/org/repo.git")).unwrap(), expected);
+            let expected = mk_url("[fe80::1]", "org", "repo", "ssh", "git");
+            assert_eq!(parse_url(String::from("git@[fe80::1]:org/repo.git")).unwrap(), expected);
+        }
+
+        #[test]
+        fn git_and_file_urls() {
+            let expected = mk_url("example.com", "org", "repo", "git", "");
+            assert_eq!(parse_url(String::from("git://example.com:9418/org/repo.git")).unwrap(), expected);
+            let expected = mk_url("", "srv/git/org", "repo", "file", "");
+            assert_eq!(parse_url(String::from("file:///srv/git/org/repo.git")).unwrap(), expected);
+            assert_eq!(parse_url(String::from("/srv/git/org/repo.git")).unwrap(), expected);
+            let expected = mk_url("", "C:/git", "repo", "file", "");
+            assert_eq!(parse_url(String::from("C:\\git\\repo.git")).unwrap(), expected);
+        }
+
+        #[test]
+        fn hosts_are_case_insensitive() {
+            let expected = mk_url("github.com", "Org", "Repo", "ssh", "git");
+            assert_eq!(parse_url(String::from("git@GitHub.com:Org/Repo.git")).unwrap(), expected);
+        }
+    }
+
+    mod url_to_domain {
+        use super::*;
+        #[test]
+        fn ports_are_part_of_the_domain() {
+            let with_port = parse_url(String::from("ssh://git@host:2222/org/repo.git")).unwrap();
+            assert_eq!(url_to_domain(&with_port), "ssh://git@host:2222");
+            let scp = parse_url(String::from("git@host:org/repo.git")).unwrap();
+            assert_eq!(url_to_domain(&scp), "ssh://git@host");
+            assert_eq!(url_to_domain(&scp), url_to_domain(&parse_url(String::from("ssh://git@host/org/repo")).unwrap()));
+        }
+    }
+
+    mod answer_key {
+        use super::*;
+        fn key(share: config::Share, url: &str) -> Option<String> {
+            answer_key(&share, &parse_url(String::from(url)).unwrap())
+        }
+
+        #[test]
+        fn follows_share() {
+            let repo = "https://me@github.com/org/repo";
+            assert_eq!(key(config::Share::Never, repo), None);
+            assert_eq!(key(config::Share::Defaults, repo), None);
+            assert_ne!(key(config::Share::Duplicate, repo), key(config::Share::Duplicate, "https://me@github.com/org/other"));
+            assert_eq!(key(config::Share::Org, repo), key(config::Share::Org, "https://me@github.com/org/other"));
+            assert_ne!(key(config::Share::Org, repo), key(config::Share::Org, "https://me@github.com/elsewhere/repo"));
+            assert_eq!(key(config::Share::Domain, repo), key(config::Share::Domain, "https://me@github.com/elsewhere/repo"));
+        }
+
+        #[test]
+        fn never_across_transports_or_users() {
+            let repo = "https://me@github.com/org/repo";
+            assert_ne!(key(config::Share::Duplicate, repo), key(config::Share::Duplicate, "http://me@github.com/org/repo"));
+            assert_ne!(key(config::Share::Domain, repo), key(config::Share::Domain, "https://you@github.com/org/repo"));
+        }
+    }
+
+    mod prompt_cred {
+        use super::*;
+        fn key(name: &str) -> String {
+            format!("{}/test/keys/{}", env!("CARGO_MANIFEST_DIR"), name)
+        }
+
+        #[test]
+        fn non_interactive_plain_needs_prompt() {
+            assert_eq!(prompt_cred(String::from("https://github.com/org/repo"), false, vec![], false), None);
+        }
+
+        #[test]
+        fn non_interactive_skips_encrypted_keys() {
+            let encrypted = GitCred::Ssh(key("encrypted_ed25519"), None);
+            let plain = GitCred::Ssh(key("plain_ed25519"), None);
+            let url = String::from("git@github.com:org/repo.git");
+            assert_eq!(prompt_cred(url.clone(), true, vec![&encrypted], false), None);
+            assert_eq!(prompt_cred(url, true, vec![&encrypted, &plain], false), Some(plain.clone()));
+        }
+    }
+
+    mod assign {
+        use super::*;
+        #[test]
+        fn assigned_cred_is_active() {
+            let mut storage = Storage {
+                repo_graph: HashMap::new(),
+                default_ssh: None,
+                default_plain: None,
+                tokens: tokens::Tokens::default(),
+                share: config::Share::Domain,
+                keys: HashSet::new(),
+                interactive: false,
+                clones: HashMap::new(),
+                usernames: Usernames::default(),
+            };
+            let git_url = parse_url(String::from("https://neallred@github.com/org/repo")).unwrap();
+            let repo_path = String::from("/repos/repo");
+            assert!(!storage.has_cred(&git_url, &repo_path));
+            storage.assign(&git_url, repo_path.clone(), GitCred::Plain(String::from("pass")));
+            assert!(storage.has_cred(&git_url, &repo_path));
+            // A second path for the same remote gets the assigned cred through sharing.
+            let other_path = String::from("/other/repo");
+            let cred = storage.get_cred(git_url.clone(), other_path, false, String::from(""));
+            assert_eq!(cred, Some(GitCred::Plain(String::from("pass"))));
+        }
+
+        #[test]
+        fn reuse_keeps_the_active_cred() {
+            let mut storage = Storage {
+                repo_graph: HashMap::new(),
+                default_ssh: None,
+                default_plain: Some(GitCred::Plain(String::from("default"))),
+                tokens: tokens::Tokens::default(),
+                share: config::Share::Never,
+                keys: HashSet::new(),
+                interactive: false,
+                clones: HashMap::new(),
+                usernames: Usernames::default(),
+            };
+            let git_url = parse_url(String::from("https://me@github.com/org/repo")).unwrap();
+            let repo_path = String::from("/repos/repo");
+            assert_eq!(storage.reuse_active(&git_url, &repo_path), None);
+            storage.assign(&git_url, repo_path.clone(), GitCred::Plain(String::from("pass")));
+            assert_eq!(storage.reuse_active(&git_url, &repo_path), Some(GitCred::Plain(String::from("pass"))));
+            // Asking again is what an auth failure does, which moves on.
+            assert_eq!(storage.get_cred(git_url.clone(), repo_path.clone(), false, String::from("")), storage.default_plain.clone());
+            assert_eq!(storage.reuse_active(&git_url, &repo_path), storage.default_plain.clone());
+        }
+    }
+
+    mod clones {
+        use super::*;
+        fn storage(share: config::Share) -> Storage {
+            Storage {
+                repo_graph: HashMap::new(),
+                default_ssh: None,
+                default_plain: None,
+                tokens: tokens::Tokens::default(),
+                share,
+                keys: HashSet::new(),
+                interactive: false,
+                clones: HashMap::new(),
+                usernames: Usernames::default(),
+            }
+        }
+
+        #[test]
+        fn identity_ignores_url_form() {
+            let https = identity("https://deploy@GitHub.com/org/repo.git");
+            assert_eq!(https, Some(String::from("github.com/org/repo")));
+            assert_eq!(identity("git@github.com:org/repo"), https);
+            assert_eq!(identity("ssh://git@github.com:22/org/repo.git/"), https);
+            assert_ne!(identity("git@github.com:org/other"), https);
+            assert_eq!(identity("/srv/git/repo.git"), Some(String::from("/srv/git/repo")));
+            assert_eq!(identity("/srv/git/../git/./repo"), Some(String::from("/srv/git/repo")));
+        }
+
+        #[test]
+        fn duplicates_share_across_url_forms() {
+            let mut storage = storage(config::Share::Duplicate);
+            let https = parse_url(String::from("https://me@github.com/org/repo.git")).unwrap();
+            storage.assign(&https, String::from("/a/repo"), GitCred::Plain(String::from("pass")));
+            let no_user = parse_url(String::from("https://github.com/org/repo")).unwrap();
+            let cred = storage.get_cred(no_user, String::from("/b/repo"), false, String::from(""));
+            assert_eq!(cred, Some(GitCred::Plain(String::from("pass"))));
+            // Not in cleartext, and not to another user.
+            let http = parse_url(String::from("http://github.com/org/repo")).unwrap();
+            assert_eq!(storage.get_cred(http, String::from("/d/repo"), false, String::from("")), None);
+            let other_user = parse_url(String::from("https://you@github.com/org/repo")).unwrap();
+            assert_eq!(storage.get_cred(other_user, String::from("/e/repo"), false, String::from("")), None);
+            // A password is no use for an ssh clone of the same repo.
+            let ssh = parse_url(String::from("git@github.com:org/repo.git")).unwrap();
+            assert_eq!(storage.get_cred(ssh, String::from("/c/repo"), true, String::from("")), None);
+        }
+
+        #[test]
+        fn defaults_level_does_not_share() {
+            let mut storage = storage(config::Share::Defaults);
+            let https = parse_url(String::from("https://me@github.com/org/repo.git")).unwrap();
+            storage.assign(&https, String::from("/a/repo"), GitCred::Plain(String::from("pass")));
+            let http = parse_url(String::from("http://github.com/org/repo")).unwrap();
+            assert_eq!(storage.get_cred(http, String::from("/b/repo"), false, String::from("")), None);
+        }
+    }
+
+    mod host_tokens {
+        use super::*;
+        #[test]
+        fn only_offered_to_their_host() {
+            let mut storage = Storage {
+                repo_graph: HashMap::new(),
+                default_ssh: None,
+                default_plain: Some(GitCred::Plain(String::from("pw"))),
+                tokens: tokens::Tokens::from_env(vec![(String::from("UPGIT_TOKEN_github_com"), String::from("ghp_1"))].into_iter()),
+                share: config::Share::Domain,
+                keys: HashSet::new(),
+                interactive: false,
+                clones: HashMap::new(),
+                usernames: Usernames::default(),
+            };
+            let token = Some(GitCred::Token(String::from("ghp_1")));
+            let github = parse_url(String::from("https://github.com/org/repo")).unwrap();
+            let gitlab = parse_url(String::from("https://gitlab.com/org/repo")).unwrap();
+            assert_eq!(storage.get_cred(github.clone(), String::from("/a/repo"), false, String::from("")), token);
+            assert_eq!(storage.get_cred(gitlab.clone(), String::from("/b/repo"), false, String::from("")), storage.default_plain.clone());
+            // The domain level shares within a host, so gitlab never sees the token.
+            assert_eq!(storage.get_cred(gitlab, String::from("/b/repo"), false, String::from("")), None);
+            // A rejected token falls back to what the host would get without one.
+            assert_eq!(storage.get_cred(github, String::from("/a/repo"), false, String::from("")), storage.default_plain.clone());
+            let ssh = parse_url(String::from("git@github.com:org/repo")).unwrap();
+            assert_eq!(storage.get_cred(ssh, String::from("/c/repo"), true, String::from("")), None);
+        }
+
+        #[test]
+        fn never_sent_over_http() {
+            let mut storage = Storage {
+                repo_graph: HashMap::new(),
+                default_ssh: None,
+                default_plain: None,
+                tokens: tokens::Tokens::from_env(vec![(String::from("UPGIT_TOKEN_github_com"), String::from("ghp_1"))].into_iter()),
+                share: config::Share::Domain,
+                keys: HashSet::new(),
+                interactive: false,
+                clones: HashMap::new(),
+                usernames: Usernames::default(),
+            };
+            let http = parse_url(String::from("http://github.com/org/repo")).unwrap();
+            assert_eq!(storage.get_cred(http, String::from("/a/repo"), false, String::from("")), None);
+        }
+    }
+
+    mod get_username {
+        use super::*;
+        fn storage(share: config::Share, configured: &[(&str, &str)]) -> Storage {
+            Storage {
+                repo_graph: HashMap::new(),
+                default_ssh: None,
+                default_plain: None,
+                tokens: tokens::Tokens::default(),
+                share,
+                keys: HashSet::new(),
+                interactive: false,
+                clones: HashMap::new(),
+                usernames: Usernames {
+                    configured: configured.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+                    resolved: HashMap::new(),
+                },
+            }
+        }
+
+        fn username(storage: &mut Storage, url: &str, repo_path: &str) -> Option<String> {
+            storage.get_username(&parse_url(String::from(url)).unwrap(), repo_path, url)
+        }
+
+        #[test]
+        fn configured_per_host() {
+            let mut storage = storage(config::Share::Never, &[("github.com", "neallred")]);
+            assert_eq!(username(&mut storage, "https://GitHub.com/org/repo", "/a/repo"), Some(String::from("neallred")));
+            assert_eq!(username(&mut storage, "https://gitlab.com/org/repo", "/b/repo"), None);
+        }
+
+        #[test]
+        fn shared_by_level() {
+            let mut storage = storage(config::Share::Org, &[("github.com", "me")]);
+            assert_eq!(username(&mut storage, "https://github.com/org/repo", "/a/repo"), Some(String::from("me")));
+            storage.usernames.configured.clear();
+            assert_eq!(username(&mut storage, "https://github.com/org/repo", "/a/repo"), Some(String::from("me")));
+            assert_eq!(username(&mut storage, "https://github.com/org/other", "/b/other"), Some(String::from("me")));
+            assert_eq!(username(&mut storage, "https://github.com/elsewhere/repo", "/c/repo"), None);
+        }
+
+        #[test]
+        fn most_specific_credential_url_wins() {
+            let path = crate::test_fixtures::TempPath::file("creds-username", "\
+[credential]\n\tusername = everyone\n\
+[credential \"https://git.corp:8443\"]\n\tusername = corp\n\
+[credential \"https://git.corp:8443/team\"]\n\tusername = team\n");
+            let config = git2::Config::open(path.path()).unwrap();
+            assert_eq!(config_username(&config, "https://git.corp:8443/team/repo.git"), Some(String::from("team")));
+            assert_eq!(config_username(&config, "https://git.corp:8443/teammate/repo.git"), Some(String::from("corp")));
+            assert_eq!(config_username(&config, "https://git.corp/team/repo.git"), Some(String::from("everyone")));
+        }
+
+        #[test]
+        fn nothing_shared_below_duplicate() {
+            let git_url = parse_url(String::from("https://github.com/org/repo")).unwrap();
+            assert!(username_keys(&config::Share::Defaults, &git_url).is_empty());
+            assert_eq!(username_keys(&config::Share::Duplicate, &git_url), vec![String::from("repo:github.com/org/repo")]);
+            assert_eq!(username_keys(&config::Share::Domain, &git_url).len(), 3);
+        }
     }
 
     mod ensure_repo_node {
@@ -510,15 +1230,19 @@ mod tests {
                 repo_graph: HashMap::new(),
                 default_ssh: None,
                 default_plain: None,
+                tokens: tokens::Tokens::default(),
                 share: config::Share::Never,
                 keys: HashSet::new(),
+                interactive: true,
+                clones: HashMap::new(),
+                usernames: Usernames::default(),
             };
 
             let url = String::from("git@gitstub.io/org/repo.git");
             let git_url = parse_url(url).unwrap();
             storage.ensure_repo_node(&git_url);
             // panics (fails test) if does not exist
-            &storage.repo_graph[&String::from("unknown://git@gitstub.io")][&String::from("org")][&String::from("repo.git")];
+            &storage.repo_graph[&String::from("unknown://git@gitstub.io")][&String::from("org")][&String::from("repo")];
         }
     }
 }
@@ -1,20 +1,121 @@
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
 use std::hash::Hash;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct End {
     path:   String,
     status: Status,
     report: String,
+    attempts: u32,
+    // This and git_error are boxed to keep the many Result<_, End> small.
+    details: Box<Details>,
+    duration: Option<Duration>,
+    git_error: Option<Box<GitError>>,
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+// What is known about the repo's branch by the time it ended. Fields stay
+// empty when the run ended before they could be looked up.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Details {
+    pub branch: Option<String>,
+    pub remote: Option<String>, // after url.<base>.insteadOf rewrites
+    pub push_remote: Option<String>,
+    pub identity: Option<String>, // the remote's host and path, the same for every url form
+    pub old_head: Option<String>,
+    pub new_head: Option<String>,
+    pub ahead: Option<usize>,
+    pub behind: Option<usize>,
+    pub changes: Vec<Change>,
+    pub diff_stats: Option<DiffStats>,
+    pub commits: Vec<Commit>,
+    pub commits_not_shown: usize,
+    pub strategy: Option<&'static str>, // fast_forward, merge or rebase
+    pub rebased: Vec<Rewrite>, // local commits that were replayed on the remote's
+    pub attention: Vec<String>, // changed files that match a watch rule
+    pub hooks: Vec<CommandRun>, // the repo's own git hooks
+    pub commands: Vec<CommandRun>, // post-update commands, in the order they ran
+}
+
+// A local commit and the one it became when rebased.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rewrite {
+    pub old: String,
+    pub new: String,
+}
+
+// What a git hook or post-update command did.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommandRun {
+    pub command: String,
+    pub exit_code: Option<i32>, // None when it was killed by a signal or could not start
+    pub output_tail: String,
+    pub duration_ms: u128,
+}
+
+impl CommandRun {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+// A commit that came in with the update, newest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Commit {
+    pub oid: String,
+    pub author: String,
+    pub subject: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub change: &'static str,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub binary: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiffStats {
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct GitError {
+    class: String,
+    code: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+// Ordered from best to worst, so a run's outcome is the max over its repos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Clean,
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Status {
     // #TODO: Should these be consolidated? Does the user care or want to know
     // all the different failures and their reasons?
     NonRepo,
     NoRemotes,
     Dirty,
+    NotFastForward,
     RemoteHeadMismatch,
     UpToDate,
     Updated,
@@ -25,39 +126,186 @@ pub enum Status {
     UnresolvedConflict,
     NeedsResolution,
     FailedFetch,
+    UnknownHostKey,
+    HostKeyChanged,
+    AuthRequired,
+    TimedOut,
+    Cancelled,
+    HookFailed, // updated, but one of the repo's git hooks failed
+    CommandFailed, // updated, but a post-update command failed
+    #[serde(rename = "other")]
     WIPOther // For unconsidered errors. This should eventually eliminated
 }
 
-pub fn non_repo(path: String, report: String) -> End {
+impl Status {
+    // Folders that are not repos were never going to be updated, so they
+    // count as fine too.
+    pub fn succeeded(&self) -> bool {
+        matches!(self, Status::UpToDate | Status::Updated | Status::NonRepo)
+    }
+
+    // Skipped repos were left as they were on purpose and need a person to
+    // decide something. Failed ones broke on the way. A changed or revoked
+    // host key may be an attack, so it fails too.
+    pub fn outcome(&self) -> Outcome {
+        if self.succeeded() {
+            return Outcome::Clean;
+        }
+        match self {
+            Status::FailedFetch
+            | Status::FailedMergeAnalysis
+            | Status::UnresolvedConflict
+            | Status::NeedsResolution
+            | Status::TimedOut
+            | Status::HookFailed
+            | Status::CommandFailed
+            | Status::HostKeyChanged
+            | Status::WIPOther => Outcome::Failed,
+            _ => Outcome::Skipped,
+        }
+    }
+
+    // Uses the same names as the JSON report, e.g. `failed_fetch`.
+    pub fn from_name(name: &str) -> Option<Status> {
+        let result: Result<Status, serde::de::value::Error> = Status::deserialize(name.into_deserializer());
+        result.ok()
+    }
+
+    pub fn as_name(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => name,
+            _ => unreachable!("statuses serialize to their names"),
+        }
+    }
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Clean => 0,
+            Outcome::Skipped => 2,
+            Outcome::Failed => 3,
+        }
+    }
+}
+
+// With a fail-on list, exactly the listed statuses are failures, and
+// anything else that did not succeed counts as skipped.
+pub fn outcome(ends: &[End], fail_on: Option<&HashSet<Status>>) -> Outcome {
+    ends.iter().map(|x| match fail_on {
+        Some(fail_on) if fail_on.contains(&x.status) => Outcome::Failed,
+        Some(_) => x.status.outcome().min(Outcome::Skipped),
+        None => x.status.outcome(),
+    }).max().unwrap_or(Outcome::Clean)
+}
+
+fn new(path: String, status: Status, report: String) -> End {
     End {
-        status: Status::NonRepo,
         path,
+        status,
         report,
+        attempts: 1,
+        details: Box::default(),
+        duration: None,
+        git_error: None,
+    }
+}
+
+pub fn non_repo(path: String, report: String) -> End {
+    new(path, Status::NonRepo, report)
+}
+
+impl End {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    pub fn report(&self) -> &str {
+        &self.report
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn details(&self) -> &Details {
+        &self.details
+    }
+
+    // How many times the fetch was tried before this outcome.
+    pub fn with_attempts(self, attempts: u32) -> End {
+        End { attempts, ..self }
+    }
+
+    pub fn with_details(self, details: Details) -> End {
+        End { details: Box::new(details), ..self }
+    }
+
+    // The update went through, but what ran after it failed.
+    pub fn with_failed_run(self, status: Status, run: &CommandRun) -> End {
+        let exit = match run.exit_code {
+            Some(code) => format!("exited with {}", code),
+            None => String::from("did not finish"),
+        };
+        let output: String = run.output_tail.lines().map(|x| format!("\n      {}", x)).collect();
+        let report = format!("`{}` {}{}{}", run.command, exit, output, self.report);
+        End { status, report, ..self }
+    }
+
+    // Post-update commands run once the rest of the update has ended, so
+    // their time is added on.
+    pub fn with_commands(mut self, commands: Vec<CommandRun>, took: Duration) -> End {
+        self.details.commands = commands;
+        self.duration = self.duration.map(|x| x + took);
+        self
+    }
+
+    pub fn with_duration(self, duration: Duration) -> End {
+        End { duration: Some(duration), ..self }
+    }
+
+    // Keeps libgit2's classification of the error that ended the run.
+    pub fn with_git_error(self, err: &git2::Error) -> End {
+        let git_error = GitError {
+            class: format!("{:?}", err.class()),
+            code: format!("{:?}", err.code()),
+        };
+        End { git_error: Some(Box::new(git_error)), ..self }
+    }
+
+    fn commit_lines(&self) -> String {
+        let mut lines: String = self.details.commits.iter()
+            .map(|x| format!("\n    {} {}: {}", x.oid, x.author, x.subject))
+            .collect();
+        if self.details.commits_not_shown > 0 {
+            lines.push_str(&format!("\n    ...and {} more commits", self.details.commits_not_shown));
+        }
+        lines
+    }
+
+    fn label(&self) -> String {
+        if self.attempts > 1 {
+            format!("{} (after {} attempts)", self.path, self.attempts)
+        } else {
+            self.path.clone()
+        }
     }
 }
 
 pub fn with_path(path: String) -> Box<dyn Fn(Status, String) -> End> {
-    return Box::new(move |status, report| End {
-        path: path.clone(),
-        status,
-        report
-    })
+    Box::new(move |status, report| new(path.clone(), status, report))
 }
 
 pub fn other(path: String) -> Box<dyn Fn(String) -> End> {
-    return Box::new(move |report| End {
-        path: path.clone(),
-        status: Status::WIPOther,
-        report
-    })
+    Box::new(move |report| new(path.clone(), Status::WIPOther, report))
 }
 
 pub fn sans_report(path: String) -> Box<dyn Fn(Status) -> End> {
-    return Box::new(move |status| End {
-        path: path.clone(),
-        status,
-        report: String::from(""),
-    })
+    Box::new(move |status| new(path.clone(), status, String::from("")))
 }
 
 fn group(ends: Vec<End>) -> HashMap<Status, Vec<End>> {
@@ -79,7 +327,7 @@ fn group(ends: Vec<End>) -> HashMap<Status, Vec<End>> {
 fn print_all(ends: &Vec<End>, label: &str) -> Option<()> {
         println!("{} ({}):", label, ends.len());
         for x in ends {
-            println!("  {}\n    {}", x.path, x.report);
+            println!("  {}\n    {}", x.label(), x.report);
         };
         None
 }
@@ -87,7 +335,7 @@ fn print_all(ends: &Vec<End>, label: &str) -> Option<()> {
 fn print_path(ends: &Vec<End>, label: &str) -> Option<()> {
         println!("{} ({}):", label, ends.len());
         for x in ends {
-            println!("  {}", x.path);
+            println!("  {}", x.label());
         };
         None
 }
@@ -110,16 +358,131 @@ pub fn print(ends: &Vec<End>) {
     groups.get(&Status::RevertedConflict).and_then(|x| print_all(x, "Reverted conflict"));
     groups.get(&Status::UnresolvedConflict).and_then(|x| print_all(x, "Unresolved conflict"));
     groups.get(&Status::Dirty).and_then(|x| print_all(x, "Dirty, skipped"));
+    groups.get(&Status::NotFastForward).and_then(|x| print_all(x, "Not fast-forward, skipped"));
     groups.get(&Status::FailedFetch).and_then(|x| print_all(x, "Couldn't fetch"));
+    groups.get(&Status::UnknownHostKey).and_then(|x| print_all(x, "Unknown host key, skipped"));
+    groups.get(&Status::HostKeyChanged).and_then(|x| print_all(x, "HOST KEY CHANGED, skipped"));
+    groups.get(&Status::AuthRequired).and_then(|x| print_all(x, "Auth required, skipped"));
+    groups.get(&Status::TimedOut).and_then(|x| print_all(x, "Timed out"));
+    groups.get(&Status::Cancelled).and_then(|x| print_all(x, "Cancelled"));
     groups.get(&Status::NeedsResolution).and_then(|x| print_all(x, "Needs resolution"));
+    groups.get(&Status::HookFailed).and_then(|x| print_all(x, "Hook failed"));
+    groups.get(&Status::CommandFailed).and_then(|x| print_all(x, "Post-update command failed"));
     groups.get(&Status::WIPOther).and_then(|x| print_all(x, "Other error"));
     groups.get(&Status::Updated).and_then(|ends| -> Option<()> {
         println!("Updated ({}):", ends.len());
         for x in ends {
-            println!("{}:{}\n", x.path, x.report);
+            println!("{}:{}{}\n", x.label(), x.commit_lines(), x.report);
         };
         None
     });
+    let duplicates = duplicates(ends);
+    if !duplicates.is_empty() {
+        println!("Duplicate clones ({}):", duplicates.len());
+        for (identity, clones) in duplicates {
+            println!("  {}", identity);
+            for x in clones {
+                println!("    {} ({})", x.path, x.details.remote.as_deref().unwrap_or(""));
+            }
+        }
+    }
+    // Last, so it is the first thing seen after the run.
+    let attention: Vec<_> = ends.iter().filter(|x| !x.details.attention.is_empty()).collect();
+    if !attention.is_empty() {
+        println!("Needs attention ({}):", attention.len());
+        for x in attention {
+            println!("  {}\n    {}", x.label(), x.details.attention.join("\n    "));
+        }
+    }
+}
+
+// Remotes cloned into more than one folder, whichever urls they were cloned with.
+fn duplicates(ends: &[End]) -> Vec<(&str, Vec<&End>)> {
+    let mut by_identity: HashMap<&str, Vec<&End>> = HashMap::new();
+    for x in ends {
+        if let Some(identity) = &x.details.identity {
+            by_identity.entry(identity).or_default().push(x);
+        }
+    }
+    let mut duplicates: Vec<_> = by_identity.into_iter().filter(|(_, clones)| clones.len() > 1).collect();
+    duplicates.sort_by_key(|(identity, _)| *identity);
+    for (_, clones) in duplicates.iter_mut() {
+        clones.sort_by_key(|x| &x.path);
+    }
+    duplicates
+}
+
+// Scripts read this, so its field names are kept apart from End's.
+#[derive(Serialize)]
+struct JsonRun<'a> {
+    version: u32,
+    git_dirs: &'a [String],
+    duration_ms: u128,
+    cancelled: bool,
+    repos: Vec<JsonRepo<'a>>,
+    duplicates: Vec<JsonDuplicate<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonDuplicate<'a> {
+    identity: &'a str,
+    paths: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonRepo<'a> {
+    path: &'a str,
+    status: &'a Status,
+    attempts: u32,
+    #[serde(flatten)]
+    details: &'a Details,
+    duration_ms: Option<u128>,
+    report: &'a str,
+    error: Option<JsonError<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    message: &'a str,
+    #[serde(flatten)]
+    git: Option<&'a GitError>,
+}
+
+impl End {
+    fn to_json(&self) -> JsonRepo<'_> {
+        JsonRepo {
+            path: &self.path,
+            status: &self.status,
+            attempts: self.attempts,
+            details: &self.details,
+            duration_ms: self.duration.map(|x| x.as_millis()),
+            report: &self.report,
+            error: if self.status.succeeded() {
+                None
+            } else {
+                Some(JsonError { message: &self.report, git: self.git_error.as_deref() })
+            },
+        }
+    }
+}
+
+fn json(ends: &[End], git_dirs: &[String], duration: Duration, cancelled: bool) -> String {
+    let run = JsonRun {
+        version: 1,
+        git_dirs,
+        duration_ms: duration.as_millis(),
+        cancelled,
+        repos: ends.iter().map(|x| x.to_json()).collect(),
+        duplicates: duplicates(ends).into_iter().map(|(identity, clones)| JsonDuplicate {
+            identity,
+            paths: clones.into_iter().map(|x| x.path.as_str()).collect(),
+        }).collect(),
+    };
+    serde_json::to_string_pretty(&run).expect("could not serialize report")
+}
+
+pub fn print_json(ends: &[End], git_dirs: &[String], duration: Duration, cancelled: bool) {
+    println!("{}", json(ends, git_dirs, duration, cancelled));
 }
 
 #[cfg(test)]
@@ -130,16 +493,13 @@ mod tests {
     use super::*;
     #[test]
     fn group_buckets_correctly() {
-        let mk_end = |status| End {
-            path: String::from(""),
-            status,
-            report: String::from(""),
-        };
+        let mk_end = |status| new(String::from(""), status, String::from(""));
         let ends = vec![
             mk_end(Status::NonRepo),
             mk_end(Status::NoRemotes),
             mk_end(Status::NoRemotes),
             mk_end(Status::Dirty),
+            mk_end(Status::NotFastForward),
             mk_end(Status::RemoteHeadMismatch),
             mk_end(Status::UpToDate),
             mk_end(Status::Updated),
@@ -150,6 +510,13 @@ mod tests {
             mk_end(Status::UnresolvedConflict),
             mk_end(Status::NeedsResolution),
             mk_end(Status::FailedFetch),
+            mk_end(Status::UnknownHostKey),
+            mk_end(Status::HostKeyChanged),
+            mk_end(Status::AuthRequired),
+            mk_end(Status::TimedOut),
+            mk_end(Status::Cancelled),
+            mk_end(Status::HookFailed),
+            mk_end(Status::CommandFailed),
             mk_end(Status::WIPOther)
         ];
         let grouped = group(ends.clone());
@@ -173,6 +540,110 @@ mod tests {
         assert_eq!(sans_report(blnk())(Status::NonRepo).report, blnk())
     }
 
+    #[test]
+    fn label_shows_retries() {
+        let end = with_path(String::from("/r"))(Status::Updated, blnk());
+        assert_eq!(end.label(), "/r");
+        assert_eq!(end.with_attempts(3).label(), "/r (after 3 attempts)");
+    }
+
+    #[test]
+    fn status_names() {
+        assert_eq!(Status::from_name("failed_fetch"), Some(Status::FailedFetch));
+        assert_eq!(Status::from_name("other"), Some(Status::WIPOther));
+        assert_eq!(Status::from_name("FailedFetch"), None);
+    }
+
+    #[test]
+    fn outcome_is_the_worst() {
+        let mk_end = |status| new(blnk(), status, blnk());
+        assert_eq!(outcome(&[], None), Outcome::Clean);
+        assert_eq!(outcome(&[mk_end(Status::UpToDate), mk_end(Status::NonRepo)], None), Outcome::Clean);
+        assert_eq!(outcome(&[mk_end(Status::Updated), mk_end(Status::Dirty)], None), Outcome::Skipped);
+        assert_eq!(outcome(&[mk_end(Status::Dirty), mk_end(Status::FailedFetch)], None), Outcome::Failed);
+        assert_eq!(outcome(&[mk_end(Status::UnknownHostKey)], None), Outcome::Skipped);
+        assert_eq!(outcome(&[mk_end(Status::HostKeyChanged)], None), Outcome::Failed);
+    }
+
+    #[test]
+    fn fail_on_decides_failures() {
+        let mk_end = |status| new(blnk(), status, blnk());
+        let fail_on: HashSet<_> = vec![Status::Dirty].into_iter().collect();
+        assert_eq!(outcome(&[mk_end(Status::Dirty)], Some(&fail_on)), Outcome::Failed);
+        assert_eq!(outcome(&[mk_end(Status::FailedFetch)], Some(&fail_on)), Outcome::Skipped);
+        assert_eq!(outcome(&[mk_end(Status::UpToDate)], Some(&fail_on)), Outcome::Clean);
+    }
+
+    #[test]
+    fn json_report() {
+        let details = Details {
+            branch: Some(String::from("main")),
+            old_head: Some(String::from("abc")),
+            new_head: Some(String::from("def")),
+            behind: Some(2),
+            changes: vec![Change { change: "added", path: String::from("f"), old_path: None, insertions: 3, deletions: 0, binary: false }],
+            ..Details::default()
+        };
+        let ends = vec![
+            with_path(String::from("/r"))(Status::Updated, blnk()).with_details(details).with_duration(Duration::from_millis(1500)),
+            other(String::from("/s"))(String::from("boom")),
+        ];
+        let doc: serde_json::Value = serde_json::from_str(&json(&ends, &[String::from("/")], Duration::from_secs(2), false)).unwrap();
+        assert_eq!(doc["duration_ms"], 2000);
+        assert_eq!(doc["repos"][0]["status"], "updated");
+        assert_eq!(doc["repos"][0]["branch"], "main");
+        assert_eq!(doc["repos"][0]["behind"], 2);
+        assert_eq!(doc["repos"][0]["changes"][0]["change"], "added");
+        assert_eq!(doc["repos"][0]["duration_ms"], 1500);
+        assert!(doc["repos"][0]["error"].is_null());
+        assert_eq!(doc["repos"][1]["status"], "other");
+        assert_eq!(doc["repos"][1]["error"]["message"], "boom");
+    }
+
+    #[test]
+    fn duplicates_group_by_identity() {
+        let clone = |path: &str, identity: Option<&str>| {
+            let details = Details { identity: identity.map(String::from), ..Details::default() };
+            with_path(String::from(path))(Status::UpToDate, blnk()).with_details(details)
+        };
+        let ends = vec![
+            clone("/g/b", Some("github.com/org/repo")),
+            clone("/g/c", Some("github.com/org/other")),
+            clone("/g/a", Some("github.com/org/repo")),
+            clone("/g/d", None),
+        ];
+        let found: Vec<_> = duplicates(&ends).into_iter()
+            .map(|(identity, clones)| (identity, clones.iter().map(|x| x.path()).collect::<Vec<_>>()))
+            .collect();
+        assert_eq!(found, vec![("github.com/org/repo", vec!["/g/a", "/g/b"])]);
+        let doc: serde_json::Value = serde_json::from_str(&json(&ends, &[], Duration::from_secs(0), false)).unwrap();
+        assert_eq!(doc["duplicates"][0]["paths"][1], "/g/b");
+    }
+
+    #[test]
+    fn commit_lines_note_the_rest() {
+        let details = Details {
+            commits: vec![Commit { oid: String::from("abc1234"), author: String::from("Ann"), subject: String::from("Fix it") }],
+            commits_not_shown: 4,
+            ..Details::default()
+        };
+        let end = with_path(String::from("/r"))(Status::Updated, blnk()).with_details(details);
+        assert_eq!(end.commit_lines(), "\n    abc1234 Ann: Fix it\n    ...and 4 more commits");
+    }
+
+    #[test]
+    fn failed_command_keeps_the_update_report() {
+        let run = CommandRun {
+            command: String::from("make"),
+            exit_code: Some(2),
+            output_tail: String::from("make: *** [all] Error 1"),
+            duration_ms: 10,
+        };
+        let end = with_path(String::from("/r"))(Status::Updated, String::from("\n    modified f")).with_failed_run(Status::CommandFailed, &run);
+        assert_eq!(end.status, Status::CommandFailed);
+        assert_eq!(end.report, "`make` exited with 2\n      make: *** [all] Error 1\n    modified f");
+    }
+
     #[test]
     fn with_path_has_path() {
         let my_path = String::from("/path/to/repo");
@@ -0,0 +1,102 @@
+use git2::Repository;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use crate::end;
+use crate::post_update;
+
+// Where git would look for hooks: core.hooksPath, relative to the working
+// tree like git does, or the hooks folder in the git dir.
+fn hooks_dir(repo: &Repository) -> PathBuf {
+    let root = repo.workdir().unwrap_or_else(|| repo.path());
+    match repo.config().and_then(|x| x.get_path("core.hooksPath")) {
+        Ok(path) => root.join(path),
+        Err(_) => repo.path().join("hooks"),
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|x| x.is_file() && x.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+// Git for Windows runs hooks through its sh, so do the same there.
+fn hook_command(path: &Path) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("sh");
+        cmd.arg(path);
+        cmd
+    } else {
+        Command::new(path)
+    }
+}
+
+// Runs the named hook with git's arguments and input, if the repo has one.
+// Like git, a hook that is not executable is ignored.
+pub fn run(repo: &Repository, name: &str, args: &[&str], input: &str) -> Option<end::CommandRun> {
+    let path = hooks_dir(repo).join(name);
+    if !is_executable(&path) {
+        return None;
+    }
+    let mut cmd = hook_command(&path);
+    cmd.args(args).current_dir(repo.workdir().unwrap_or_else(|| repo.path()));
+    Some(post_update::capture(cmd, name, input))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::test_fixtures::TempPath;
+
+    fn write_hook(dir: &Path, name: &str, script: &str, mode: u32) {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn temp_repo(name: &str) -> (TempPath, Repository) {
+        let dir = TempPath::new(&format!("hooks-{}", name));
+        let repo = Repository::init(dir.path()).unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn runs_hooks_with_args() {
+        let (_dir, repo) = temp_repo("args");
+        write_hook(&repo.path().join("hooks"), "post-merge", "#!/bin/sh\necho \"merged $1\"\nexit 1\n", 0o755);
+        let run = run(&repo, "post-merge", &["0"], "").unwrap();
+        assert_eq!(run.exit_code, Some(1));
+        assert_eq!(run.output_tail, "merged 0");
+    }
+
+    #[test]
+    fn honours_hooks_path() {
+        let (dir, repo) = temp_repo("path");
+        repo.config().unwrap().set_str("core.hooksPath", ".husky").unwrap();
+        write_hook(&dir.path().join(".husky"), "post-checkout", "#!/bin/sh\necho \"$3\"\n", 0o755);
+        assert_eq!(run(&repo, "post-checkout", &["a", "b", "1"], "").unwrap().output_tail, "1");
+    }
+
+    #[test]
+    fn passes_input() {
+        let (_dir, repo) = temp_repo("input");
+        write_hook(&repo.path().join("hooks"), "post-rewrite", "#!/bin/sh\necho \"$1\"\ncat\n", 0o755);
+        assert_eq!(run(&repo, "post-rewrite", &["rebase"], "a b\n").unwrap().output_tail, "rebase\na b");
+    }
+
+    #[test]
+    fn skips_missing_and_non_executable_hooks() {
+        let (_dir, repo) = temp_repo("skip");
+        write_hook(&repo.path().join("hooks"), "post-merge", "#!/bin/sh\nexit 1\n", 0o644);
+        assert!(run(&repo, "post-merge", &["0"], "").is_none());
+        assert!(run(&repo, "post-checkout", &["a", "b", "1"], "").is_none());
+    }
+}
@@ -0,0 +1,293 @@
+use openssl::base64;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::sync::Mutex;
+use crate::keys;
+use crate::string_ops;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Policy {
+    Strict,
+    AcceptNew,
+    Off,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Known,
+    Unknown,
+    Changed,
+    Revoked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Marker {
+    None,
+    CertAuthority,
+    Revoked,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    marker: Marker,
+    hosts: String, // comma separated patterns, or a single |1|salt|hash
+    key_type: String,
+    key: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct KnownHosts {
+    policy: Policy,
+    entries: Mutex<Vec<Entry>>,
+    user_file: String,
+}
+
+// Host names are stored bare for port 22, and as [host]:port otherwise.
+pub fn host_key_name(host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(p) if p != 22 => format!("[{}]:{}", host, p),
+        _ => host.to_string(),
+    }
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let pkey = PKey::hmac(key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &pkey).ok()?;
+    signer.update(data).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+fn hashed_matches(hosts: &str, name: &str) -> bool {
+    let parts: Vec<_> = hosts.split('|').collect();
+    if parts.len() != 4 || parts[1] != "1" {
+        return false;
+    }
+    match (base64::decode_block(parts[2]), base64::decode_block(parts[3])) {
+        (Ok(salt), Ok(expected)) => hmac_sha1(&salt, name.as_bytes()) == Some(expected),
+        _ => false,
+    }
+}
+
+// A negated pattern that matches excludes the host, even if another pattern matches.
+fn patterns_match(hosts: &str, name: &str) -> bool {
+    if hosts.starts_with('|') {
+        return hashed_matches(hosts, name);
+    }
+    let mut matched = false;
+    for pattern in hosts.split(',') {
+        if let Some(negated) = pattern.strip_prefix('!') {
+            if string_ops::wildcard_match(negated, name) {
+                return false;
+            }
+        } else if string_ops::wildcard_match(pattern, name) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let mut fields = line.split_whitespace();
+    let mut first = fields.next()?;
+    let marker = match first {
+        "@cert-authority" => Marker::CertAuthority,
+        "@revoked" => Marker::Revoked,
+        _ => Marker::None,
+    };
+    if marker != Marker::None {
+        first = fields.next()?;
+    }
+    let key_type = fields.next()?.to_string();
+    let key = base64::decode_block(fields.next()?).ok()?;
+    // Hashed entries are base64, so only plain patterns are case folded.
+    let hosts = if first.starts_with('|') { first.to_string() } else { first.to_lowercase() };
+    Some(Entry {
+        marker,
+        hosts,
+        key_type,
+        key,
+    })
+}
+
+fn parse(contents: &str) -> Vec<Entry> {
+    contents.lines().filter_map(parse_line).collect()
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    hash(MessageDigest::sha256(), data).map(|x| x.to_vec()).unwrap_or_default()
+}
+
+// Like ssh, a key only counts as changed when a key of the same type is known.
+// Without the key type, any other key for the host counts.
+fn check_entries(entries: &[Entry], name: &str, key_type: Option<&str>, key_sha256: &[u8]) -> Verdict {
+    let name = name.to_lowercase();
+    let mut verdict = Verdict::Unknown;
+    let candidates = entries.iter()
+        .filter(|x| key_type.map(|t| t == x.key_type).unwrap_or(true))
+        .filter(|x| patterns_match(&x.hosts, &name));
+    for entry in candidates {
+        let same_key = sha256(&entry.key) == key_sha256;
+        match entry.marker {
+            Marker::Revoked if same_key => return Verdict::Revoked,
+            Marker::None if same_key => verdict = Verdict::Known,
+            Marker::None if verdict == Verdict::Unknown => verdict = Verdict::Changed,
+            _ => {},
+        }
+    }
+    verdict
+}
+
+// ssh reports fingerprints as unpadded base64 of the sha256.
+pub fn fingerprint(key_sha256: &[u8]) -> String {
+    format!("SHA256:{}", base64::encode_block(key_sha256).trim_end_matches('='))
+}
+
+// Only urls with a scheme can carry a port; scp-like urls are always port 22.
+pub fn port_from_url(remote_url: &str) -> Option<u16> {
+    if !remote_url.contains("://") {
+        return None;
+    }
+    url::Url::parse(remote_url).ok().and_then(|x| x.port())
+}
+
+impl KnownHosts {
+    pub fn load(policy: Policy) -> KnownHosts {
+        let user_file = format!("{}/known_hosts", keys::ssh_dir());
+        let mut entries = vec![];
+        for path in &[user_file.as_str(), "/etc/ssh/ssh_known_hosts"] {
+            if let Ok(contents) = fs::read_to_string(path) {
+                entries.append(&mut parse(&contents));
+            }
+        }
+        KnownHosts {
+            policy,
+            entries: Mutex::new(entries),
+            user_file,
+        }
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    // The key is given as the sha256 of its wire encoding, which is all libgit2
+    // is guaranteed to provide. The raw key is only needed to record new hosts.
+    pub fn check(&self, name: &str, key_sha256: &[u8], raw_key: Option<&[u8]>) -> Verdict {
+        let mut entries = self.entries.lock().expect("could not acquire known hosts lock");
+        let key_type = raw_key.and_then(|x| keys::SshReader::new(x).string());
+        let verdict = check_entries(&entries, name, key_type.as_deref(), key_sha256);
+        if verdict != Verdict::Unknown || self.policy != Policy::AcceptNew {
+            return verdict;
+        }
+        let (raw_key, key_type) = match (raw_key, key_type) {
+            (Some(raw_key), Some(key_type)) => (raw_key, key_type),
+            _ => return verdict,
+        };
+        let line = format!("{} {} {}", name, key_type, base64::encode_block(raw_key));
+        let appended = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.user_file)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        match appended {
+            Ok(_) => {
+                entries.extend(parse_line(&line));
+                Verdict::Known
+            },
+            Err(_) => verdict,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ED25519: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIMv9Mcrx1/UBdGEnxEMyEzZkNKK6tqK8UPx3l1vqAK6b";
+    const RSA: &str = "AAAAB3NzaC1yc2EAAAADAQABAAABAQCyagswXyFizB6/M2btZMis6XFlUzld";
+
+    fn key_hash(key: &str) -> Vec<u8> {
+        sha256(&base64::decode_block(key).unwrap())
+    }
+
+    #[test]
+    fn plain_host_known() {
+        let entries = parse(&format!("github.com,140.82.112.3 ssh-ed25519 {}\n", ED25519));
+        assert_eq!(check_entries(&entries, "github.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Known);
+        assert_eq!(check_entries(&entries, "GitHub.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Known);
+    }
+
+    #[test]
+    fn changed_key() {
+        let entries = parse(&format!("github.com ssh-ed25519 {}\n", ED25519));
+        assert_eq!(check_entries(&entries, "github.com", None, &key_hash(RSA)), Verdict::Changed);
+    }
+
+    #[test]
+    fn unknown_host() {
+        let entries = parse(&format!("github.com ssh-ed25519 {}\n", ED25519));
+        assert_eq!(check_entries(&entries, "gitlab.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Unknown);
+    }
+
+    #[test]
+    fn other_key_type_is_unknown() {
+        let entries = parse(&format!("github.com ssh-rsa {}\n", RSA));
+        assert_eq!(check_entries(&entries, "github.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Unknown);
+    }
+
+    #[test]
+    fn fingerprint_format() {
+        assert_eq!(fingerprint(&[0u8; 32]), "SHA256:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+    }
+
+    #[test]
+    fn ports_from_urls() {
+        assert_eq!(port_from_url("ssh://git@git.corp:2222/org/repo.git"), Some(2222));
+        assert_eq!(port_from_url("ssh://git@git.corp/org/repo.git"), None);
+        assert_eq!(port_from_url("git@github.com:org/repo.git"), None);
+    }
+
+    #[test]
+    fn any_matching_key_is_known() {
+        let entries = parse(&format!("github.com ssh-rsa {}\ngithub.com ssh-ed25519 {}\n", RSA, ED25519));
+        assert_eq!(check_entries(&entries, "github.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Known);
+    }
+
+    #[test]
+    fn hashed_host() {
+        // `ssh-keygen -H` output for github.com
+        let entries = parse(&format!("|1|z/ytlXXOcD3y/S5gXxggnoqu1lU=|UlXbAaqFNK4TzwI1e2HvU+GIm34= ssh-ed25519 {}\n", ED25519));
+        assert_eq!(check_entries(&entries, "github.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Known);
+        assert_eq!(check_entries(&entries, "gitlab.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Unknown);
+    }
+
+    #[test]
+    fn ports_and_wildcards() {
+        let entries = parse(&format!("[git.corp]:2222,*.example.com,!bad.example.com ssh-ed25519 {}\n", ED25519));
+        let name = host_key_name("git.corp", Some(2222));
+        assert_eq!(check_entries(&entries, &name, Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Known);
+        assert_eq!(check_entries(&entries, "git.corp", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Unknown);
+        assert_eq!(check_entries(&entries, "a.example.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Known);
+        assert_eq!(check_entries(&entries, "bad.example.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Unknown);
+    }
+
+    #[test]
+    fn revoked_key() {
+        let entries = parse(&format!("github.com ssh-ed25519 {}\n@revoked * ssh-ed25519 {}\n", ED25519, ED25519));
+        assert_eq!(check_entries(&entries, "github.com", Some("ssh-ed25519"), &key_hash(ED25519)), Verdict::Revoked);
+    }
+
+    #[test]
+    fn skips_comments_and_junk() {
+        let entries = parse("# comment\n\nnot-enough-fields\n");
+        assert_eq!(entries.len(), 0);
+    }
+}
@@ -1,58 +1,339 @@
 use git2::{Repository};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::fs;
-use std::io;
-use std::io::prelude::*;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::path::Path;
-use tokio;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+mod broker;
+mod cancel;
 mod end;
 mod config;
 mod creds;
+mod hooks;
+mod keys;
+mod known_hosts;
+mod pool;
+mod post_update;
+mod progress;
+mod pull;
+mod retry;
+mod state;
 mod string_ops;
+mod tokens;
+#[cfg(test)]
+mod test_fixtures;
+mod url_rewrite;
+mod watch;
+mod watchdog;
 
 // TODO Should this attempt to update submodules of repos with submodules?
 // Maybe as a configurable option?
 // E.g. [redox](https://gitlab.com/redox-os.org/redox-os/redox)
 
-type SharedData = Arc<Mutex<creds::Storage>>;
+// What every repo's run needs from the rest of the program.
+struct RunContext {
+    broker: broker::Broker,
+    known_hosts: known_hosts::KnownHosts,
+    fetch_timeout: Option<Duration>,
+    watchdog: watchdog::Watchdog,
+    retries: u32,
+    log_limit: usize,
+    watch: watch::Watch,
+    post_update: post_update::PostUpdate,
+    hooks: bool,
+    pull: pull::Overrides,
+    cancel: cancel::Cancel,
+    progress: progress::Progress,
+}
 
-fn do_fetch<'a>(
-    repo: &'a git2::Repository,
+// Sleeps, but wakes up early if the run is cancelled.
+fn wait(ctx: &RunContext, duration: Duration) {
+    let until = Instant::now() + duration;
+    while !ctx.cancel.is_cancelled() && Instant::now() < until {
+        thread::sleep(until.saturating_duration_since(Instant::now()).min(Duration::from_millis(100)));
+    }
+}
+
+// Checking out a big tree takes a while too, so it reports progress.
+fn checkout_opts<'a>(progress: &'a progress::Progress, repo_path: &'a str) -> git2::build::CheckoutBuilder<'a> {
+    let mut opts = git2::build::CheckoutBuilder::default();
+    opts.progress(move |_, files, total_files| progress.checkout(repo_path, files, total_files));
+    opts
+}
+
+fn check_host_key(
+    known_hosts: &known_hosts::KnownHosts,
+    cert: &git2::cert::Cert,
+    hostname: &str,
+    port: Option<u16>,
+    callback_end: &RefCell<Option<(end::Status, String)>>,
+) -> Result<git2::CertificateCheckStatus, git2::Error> {
+    // x509 certs are left to libgit2's own verification.
+    let hostkey = match cert.as_hostkey() {
+        Some(x) => x,
+        None => return Ok(git2::CertificateCheckStatus::CertificatePassthrough),
+    };
+    if known_hosts.policy() == &known_hosts::Policy::Off {
+        return Ok(git2::CertificateCheckStatus::CertificateOk);
+    }
+    let name = known_hosts::host_key_name(hostname, port);
+    let key_sha256 = match hostkey.hash_sha256() {
+        Some(x) => x,
+        None => {
+            let msg = format!("No sha256 host key available for {}", name);
+            callback_end.replace(Some((end::Status::UnknownHostKey, msg.clone())));
+            return Err(git2::Error::from_str(&msg));
+        },
+    };
+    let fingerprint = known_hosts::fingerprint(key_sha256);
+    let (status, msg) = match known_hosts.check(&name, key_sha256, hostkey.hostkey()) {
+        known_hosts::Verdict::Known => return Ok(git2::CertificateCheckStatus::CertificateOk),
+        known_hosts::Verdict::Unknown => (
+            end::Status::UnknownHostKey,
+            format!("Host {} is not in known_hosts. Its key fingerprint is {}", name, fingerprint),
+        ),
+        known_hosts::Verdict::Changed => (
+            end::Status::HostKeyChanged,
+            format!("Host key for {} does not match known_hosts. It may be impersonated. Offered key fingerprint is {}", name, fingerprint),
+        ),
+        known_hosts::Verdict::Revoked => (
+            end::Status::HostKeyChanged,
+            format!("Host key for {} is marked @revoked in known_hosts. Offered key fingerprint is {}", name, fingerprint),
+        ),
+    };
+    callback_end.replace(Some((status, msg.clone())));
+    Err(git2::Error::from_str(&msg))
+}
+
+// A fetch that failed, with the reason a callback gave for failing it, if any.
+type FetchError = (git2::Error, Option<(end::Status, String)>);
+
+fn fetch_once(
     refs: &[&str],
-    remote: &'a mut git2::Remote,
-    local_branch_name: &str,
-    shared_data: SharedData,
-    repo_path: &String,
-) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
+    remote: &mut git2::Remote,
+    prune: bool,
+    ctx: &RunContext,
+    repo_path: &str,
+) -> Result<(), FetchError> {
+    // Callbacks can only fail with a git2::Error, so they note why here.
+    let callback_end = RefCell::new(None);
+    let started = Cell::new(Instant::now());
+    // Only calls after the first in this attempt mean a credential was refused.
+    let asked = Cell::new(false);
+    // Progress callbacks return false to make libgit2 abort the transfer.
+    // Nothing in the working tree has been touched yet, so that is safe.
+    let keep_going = || {
+        if ctx.watchdog.is_abandoned(repo_path) {
+            callback_end.replace(Some((end::Status::TimedOut, String::from("Gave up on the fetch"))));
+            return false;
+        }
+        if ctx.cancel.is_cancelled() {
+            callback_end.replace(Some((end::Status::Cancelled, String::from("Fetch interrupted"))));
+            return false;
+        }
+        match ctx.fetch_timeout {
+            Some(limit) if started.get().elapsed() > limit => {
+                let msg = format!("Fetch took longer than {} seconds", limit.as_secs());
+                callback_end.replace(Some((end::Status::TimedOut, msg)));
+                false
+            },
+            _ => true,
+        }
+    };
+    let port = remote.url().and_then(known_hosts::port_from_url);
     let mut cb = git2::RemoteCallbacks::new();
 
     let mut fo = git2::FetchOptions::new();
-    cb.credentials(|url, username, allowed_types| creds::callback(url, username, allowed_types, Arc::clone(&shared_data), repo_path) );
+    cb.credentials(|url, username, allowed_types| {
+        ctx.watchdog.waiting(repo_path);
+        let cred = creds::callback(url, username, allowed_types, &ctx.broker, repo_path, !asked.replace(true));
+        ctx.watchdog.fetching(repo_path);
+        // An answer typed after Ctrl-C is not used.
+        if ctx.cancel.is_cancelled() {
+            callback_end.replace(Some((end::Status::Cancelled, String::from("Cancelled while asking for credentials"))));
+            return Err(git2::Error::from_str("cancelled"));
+        }
+        if let Err(err) = &cred {
+            if creds::is_auth_required(err) {
+                callback_end.replace(Some((end::Status::AuthRequired, err.message().to_string())));
+            }
+        }
+        // Waiting on a prompt is not the remote's fault.
+        started.set(Instant::now());
+        cred
+    });
+    cb.transfer_progress(|stats| {
+        ctx.progress.fetching(repo_path, &stats);
+        keep_going()
+    });
+    cb.sideband_progress(|_| keep_going());
+    cb.certificate_check(|cert, hostname| check_host_key(&ctx.known_hosts, cert, hostname, port, &callback_end));
 
     fo.remote_callbacks(cb);
     // Always fetch all tags.
     // Perform a download and also update tips
     fo.download_tags(git2::AutotagOption::All);
-    remote.fetch(refs, Some(&mut fo), None)?;
-
-    // If there are local objects (we got a thin pack), then tell the user
-    // how many objects we saved from having to cross the network.
-    let stats = remote.stats();
-    if stats.local_objects() > 0 && stats.received_bytes() > 0 {
-        println!(
-            "\rReceived {}/{} objects in {} bytes (used {} local \
-            objects)",
-            stats.received_objects(),
-            stats.total_objects(),
-            stats.indexed_objects(),
-            stats.received_bytes(),
-        );
-    }
-
-    let local_branch = repo.find_branch(local_branch_name, git2::BranchType::Local)?;
-    let upstream_branch = local_branch.upstream()?;
-    Ok(repo.reference_to_annotated_commit(upstream_branch.get())?)
+    fo.prune(if prune { git2::FetchPrune::On } else { git2::FetchPrune::Off });
+    ctx.watchdog.fetching(repo_path);
+    remote.fetch(refs, Some(&mut fo), None).map_err(|err| (err, callback_end.borrow_mut().take()))
+}
+
+fn do_fetch<'a>(
+    repo: &'a git2::Repository,
+    refs: &[&str],
+    remote: &'a mut git2::Remote,
+    local_branch_name: &str,
+    prune: bool,
+    ctx: &RunContext,
+    repo_path: &str,
+) -> Result<(git2::AnnotatedCommit<'a>, u32), end::End> {
+    let mk_end = end::with_path(repo_path.to_string());
+    let mut attempt = 1;
+    loop {
+        if ctx.cancel.is_cancelled() {
+            return Err(mk_end(end::Status::Cancelled, String::from("Cancelled before fetching")).with_attempts(attempt));
+        }
+        match fetch_once(refs, remote, prune, ctx, repo_path) {
+            Ok(()) => break,
+            // A stalled transfer may well go through on the next try.
+            Err((_, Some((end::Status::TimedOut, _)))) if attempt <= ctx.retries && !ctx.watchdog.is_abandoned(repo_path) => {
+                ctx.watchdog.waiting(repo_path);
+                wait(ctx, retry::backoff(attempt));
+                attempt += 1;
+            },
+            // Otherwise callbacks only fail a fetch on purpose, so trying again would not help.
+            Err((_, Some((status, msg)))) => return Err(mk_end(status, msg).with_attempts(attempt)),
+            Err((err, None)) => {
+                if attempt > ctx.retries || retry::classify(&err) == retry::Kind::Permanent {
+                    return Err(mk_end(end::Status::FailedFetch, format!("{:?}", err)).with_attempts(attempt).with_git_error(&err));
+                }
+                ctx.watchdog.waiting(repo_path);
+                wait(ctx, retry::backoff(attempt));
+                attempt += 1;
+            },
+        }
+    }
+
+    repo.find_branch(local_branch_name, git2::BranchType::Local)
+        .and_then(|local_branch| local_branch.upstream())
+        .and_then(|upstream_branch| repo.reference_to_annotated_commit(upstream_branch.get()))
+        .map(|commit| (commit, attempt))
+        .map_err(|err| mk_end(end::Status::FailedFetch, format!("{:?}", err)).with_attempts(attempt).with_git_error(&err))
+}
+
+fn change_name(delta: git2::Delta) -> &'static str {
+    match delta {
+        git2::Delta::Unmodified => "unmodified",
+        git2::Delta::Added => "added",
+        git2::Delta::Deleted => "deleted",
+        git2::Delta::Modified => "modified",
+        git2::Delta::Renamed => "renamed",
+        git2::Delta::Copied => "copied",
+        git2::Delta::Ignored => "ignored",
+        git2::Delta::Untracked => "untracked",
+        git2::Delta::Typechange => "typechange",
+        git2::Delta::Unreadable => "unreadable",
+        git2::Delta::Conflicted => "conflicted",
+    }
+}
+
+fn delta_line(status: git2::Delta, old_file: &str, new_file: &str) -> String {
+    match status {
+        git2::Delta::Unmodified => String::new(),
+        git2::Delta::Added => format!("+: {}", new_file),
+        git2::Delta::Deleted => format!("-: {}", old_file),
+        git2::Delta::Modified => format!("Δ: {}", new_file),
+        git2::Delta::Renamed => format!("→: \"{}\" -> \"{}\"", old_file, new_file),
+        git2::Delta::Copied => format!("cpy: \"{}\" -> \"{}\"", old_file, new_file),
+        git2::Delta::Ignored => format!("ign: {}", new_file),
+        git2::Delta::Untracked => format!("nochg: {}", new_file),
+        git2::Delta::Typechange => format!("chgtype: {}", new_file),
+        git2::Delta::Unreadable => format!("unreadable: {}", new_file),
+        git2::Delta::Conflicted => format!("cflct: {}", new_file),
+    }
+}
+
+fn diff_file_path(file: git2::DiffFile) -> String {
+    file.path().unwrap_or(Path::new("/unknown")).display().to_string()
+}
+
+fn diff_changes(
+    repo: &Repository,
+    old_tree: &git2::Tree,
+    new_tree: &git2::Tree,
+    details: &mut end::Details,
+) -> Result<Vec<String>, git2::Error> {
+    let mut opts = git2::DiffOptions::new();
+    opts.minimal(true);
+    let mut diff = repo.diff_tree_to_tree(Some(old_tree), Some(new_tree), Some(&mut opts))?;
+    // Without this, renames and copies show up as an add and a delete.
+    diff.find_similar(Some(git2::DiffFindOptions::new().renames(true).copies(true)))?;
+    let mut lines = vec![];
+    for idx in 0..diff.deltas().len() {
+        // Loading the patch is what tells libgit2 whether the file is binary.
+        let patch = git2::Patch::from_diff(&diff, idx)?;
+        let delta = match &patch {
+            Some(patch) => patch.delta(),
+            None => diff.get_delta(idx).expect("delta index is in range"),
+        };
+        let status = delta.status();
+        if status == git2::Delta::Unmodified {
+            continue;
+        }
+        let old_file = diff_file_path(delta.old_file());
+        let new_file = diff_file_path(delta.new_file());
+        let binary = delta.flags().is_binary();
+        let (insertions, deletions) = match &patch {
+            Some(patch) if !binary => patch.line_stats().map(|(_, x, y)| (x, y))?,
+            _ => (0, 0),
+        };
+        let counts = if binary {
+            String::from(" (binary)")
+        } else if insertions + deletions > 0 {
+            format!(" (+{} -{})", insertions, deletions)
+        } else {
+            String::from("")
+        };
+        lines.push(format!("{}{}", delta_line(status, &old_file, &new_file), counts));
+        details.changes.push(end::Change {
+            change: change_name(status),
+            old_path: if old_file != new_file { Some(old_file) } else { None },
+            path: new_file,
+            insertions,
+            deletions,
+            binary,
+        });
+    }
+    let stats = diff.stats()?;
+    if stats.files_changed() > 0 {
+        lines.push(format!(
+            "{} files changed, {} insertions(+), {} deletions(-)",
+            stats.files_changed(),
+            stats.insertions(),
+            stats.deletions(),
+        ));
+        details.diff_stats = Some(end::DiffStats {
+            files_changed: stats.files_changed(),
+            insertions: stats.insertions(),
+            deletions: stats.deletions(),
+        });
+    }
+    Ok(lines)
+}
+
+// The report for an update: one line per changed file, then the totals.
+fn describe_changes(
+    repo: &Repository,
+    old_tree: &git2::Tree,
+    new_tree: &git2::Tree,
+    details: &mut end::Details,
+) -> String {
+    let lines = diff_changes(repo, old_tree, new_tree, details)
+        .unwrap_or_else(|err| vec![format!("Could not list changes: {}", err)]);
+    lines.iter().map(|x| format!("\n    {}", x)).collect()
 }
 
 fn fast_forward(
@@ -60,40 +341,14 @@ fn fast_forward(
     lb: &mut git2::Reference,
     rc: &git2::AnnotatedCommit,
     repo_path: String,
+    progress: &progress::Progress,
+    details: &mut end::Details,
 ) -> end::End {
     let mk_end = end::with_path(repo_path.clone());
     let mk_other_end = end::other(repo_path.clone());
     let remote_tree = repo.find_commit(rc.id()).expect("fast forward error").tree().expect("ffw error");
     let local_tree = lb.peel_to_tree().expect("peel to tree error");
-    let mut opts = git2::DiffOptions::new();
-    opts.minimal(true);
-    let the_diff = repo.diff_tree_to_tree(Some(&local_tree), Some(&remote_tree), Some(&mut opts)).expect("diff tree error");
-    let mut diff_report = vec![String::from("")];
-    the_diff.print(
-         git2::DiffFormat::NameStatus,
-         |diff_delta, _option_diff_hunk, _diff_line| {
-             let status = diff_delta.status();
-             let old_file = diff_delta.old_file().path().unwrap_or(Path::new("/unknown")).display();
-             let new_file = diff_delta.new_file().path().unwrap_or(Path::new("/unknown")).display();
-             let report_str = match status {
-                 git2::Delta::Unmodified => format!(""),
-                 git2::Delta::Added => format!("+: {}", new_file),
-                 git2::Delta::Deleted => format!("-: {}", old_file),
-                 git2::Delta::Modified => format!("Δ: {}", new_file),
-                 git2::Delta::Renamed => format!("→: \"{}\" -> \"{}\"", old_file, new_file),
-                 git2::Delta::Copied => format!("cpy: \"{}\" -> \"{}\"", old_file, new_file),
-                 git2::Delta::Ignored => format!("ign: {}", new_file),
-                 git2::Delta::Untracked => format!("nochg: {}", new_file),
-                 git2::Delta::Typechange => format!("chgtype: {}", new_file),
-                 git2::Delta::Unreadable => format!("unreadable: {}", new_file),
-                 git2::Delta::Conflicted => format!("cflct: {}", new_file),
-             };
-
-             diff_report.push(report_str);
-
-             true
-         }
-    ).expect("diff print error");
+    let diff_report = describe_changes(repo, &local_tree, &remote_tree, details);
 
     let name = match lb.name() {
         Some(s) => s.to_string(),
@@ -110,12 +365,12 @@ fn fast_forward(
          return mk_other_end(format!("Unable to set head:\n    {}", err))
     }
     match repo.checkout_head(Some(
-        git2::build::CheckoutBuilder::default()
+        checkout_opts(progress, &repo_path)
             // force required to make the working directory actually get updated/
             // could add logic to handle dirty working directory states
             .force(),
     )) {
-        Ok(()) => mk_end(end::Status::Updated, diff_report.join("\n    ")),
+        Ok(()) => mk_end(end::Status::Updated, diff_report),
         Err(err) => mk_end(
             end::Status::NeedsResolution,
             format!("Unable to checkout head. This repo may need manual resolving. Oops.\n    {}", err),
@@ -128,6 +383,8 @@ fn normal_merge(
     local: &git2::AnnotatedCommit,
     remote: &git2::AnnotatedCommit,
     repo_path: String,
+    progress: &progress::Progress,
+    details: &mut end::Details,
 ) -> end::End {
     let mk_end = end::with_path(repo_path.clone());
     let mk_other_end = end::other(repo_path.clone());
@@ -166,7 +423,7 @@ fn normal_merge(
     };
 
     if idx.has_conflicts() {
-        match repo.checkout_index(Some(&mut idx), None) {
+        match repo.checkout_index(Some(&mut idx), Some(&mut checkout_opts(progress, &repo_path))) {
             Ok(()) => return mk_end(end::Status::RevertedConflict, format!("")),
             Err(err) => return mk_end(end::Status::UnresolvedConflict, format!("{}", err)),
         };
@@ -215,12 +472,90 @@ fn normal_merge(
         return mk_other_end(format!("Unable to make commit\n    {}", err))
     };
 
-    // Set working tree to match head.
-    match repo.checkout_head(None) {
+    // Set working tree to match head. HEAD already points at the merge, so a
+    // safe checkout would take the old files for local changes and keep them.
+    // The repo was checked to be clean before fetching.
+    match repo.checkout_head(Some(checkout_opts(progress, &repo_path).force())) {
         Err(err) => mk_other_end(
             format!("Unable to checkout head\n    {}", err),
         ),
-        _ => mk_end(end::Status::Updated, format!(""))
+        _ => mk_end(end::Status::Updated, describe_changes(repo, &local_tree, &result_tree, details))
+    }
+}
+
+fn conflicted_paths(repo: &Repository) -> Vec<String> {
+    let conflicts = match repo.index().and_then(|x| x.conflicts().map(|c| c.collect::<Vec<_>>())) {
+        Ok(x) => x,
+        Err(_) => return vec![],
+    };
+    conflicts.into_iter().filter_map(|conflict| {
+        let entry = conflict.ok()?;
+        let side = entry.our.or(entry.their).or(entry.ancestor)?;
+        Some(String::from_utf8_lossy(&side.path).to_string())
+    }).collect()
+}
+
+// Replays local commits on top of the fetched ones, like `git pull --rebase`.
+// A conflict aborts the rebase, which leaves the branch as it was.
+fn rebase(
+    repo: &Repository,
+    upstream: &git2::AnnotatedCommit,
+    repo_path: String,
+    progress: &progress::Progress,
+    details: &mut end::Details,
+) -> end::End {
+    let mk_end = end::with_path(repo_path.clone());
+    let mk_other_end = end::other(repo_path.clone());
+    let old_tree = match repo.head().and_then(|x| x.peel_to_tree()) {
+        Ok(x) => x,
+        Err(err) => return mk_other_end(format!("Unable to find local tree\n    {}", err)),
+    };
+    let sig = match repo.signature() {
+        Ok(x) => x,
+        Err(err) => return mk_other_end(format!("Could not find signature\n    {}", err)),
+    };
+    let mut opts = git2::RebaseOptions::new();
+    opts.checkout_options(checkout_opts(progress, &repo_path));
+    let mut rebase = match repo.rebase(None, Some(upstream), None, Some(&mut opts)) {
+        Ok(x) => x,
+        Err(err) => return mk_other_end(format!("Unable to start rebase\n    {}", err)).with_git_error(&err),
+    };
+    let mut rebased = vec![];
+    while let Some(op) = rebase.next() {
+        let old = match op {
+            Ok(op) => op.id(),
+            Err(err) => {
+                let _ = rebase.abort();
+                return mk_other_end(format!("Unable to apply a commit while rebasing, rebase aborted\n    {}", err)).with_git_error(&err)
+            },
+        };
+        let conflicts = conflicted_paths(repo);
+        if !conflicts.is_empty() {
+            let _ = rebase.abort();
+            return mk_end(end::Status::RevertedConflict, format!(
+                "Rebasing {} conflicted, rebase aborted:\n    {}",
+                old,
+                conflicts.join("\n    "),
+            ))
+        }
+        match rebase.commit(None, &sig, None) {
+            Ok(new) => rebased.push(end::Rewrite { old: old.to_string(), new: new.to_string() }),
+            // The remote already has this change, so the commit is dropped.
+            Err(err) if err.code() == git2::ErrorCode::Applied => {},
+            Err(err) => {
+                let _ = rebase.abort();
+                return mk_other_end(format!("Unable to commit while rebasing, rebase aborted\n    {}", err)).with_git_error(&err)
+            },
+        }
+    }
+    if let Err(err) = rebase.finish(Some(&sig)) {
+        return mk_other_end(format!("Unable to finish rebase\n    {}", err)).with_git_error(&err)
+    }
+    let summary = format!("\n    Rebased {} local commits", rebased.len());
+    details.rebased = rebased;
+    match repo.head().and_then(|x| x.peel_to_tree()) {
+        Ok(new_tree) => mk_end(end::Status::Updated, summary + &describe_changes(repo, &old_tree, &new_tree, details)),
+        Err(err) => mk_other_end(format!("Rebased, but unable to find the new tree\n    {}", err)),
     }
 }
 
@@ -228,21 +563,27 @@ fn do_merge<'a>(
     repo: &'a Repository,
     remote_branch: &str,
     fetch_commit: git2::AnnotatedCommit<'a>,
-    repo_path: String
+    repo_path: String,
+    strategy: pull::Strategy,
+    progress: &progress::Progress,
+    details: &mut end::Details,
 ) -> end::End {
     let mk_end = end::with_path(repo_path.clone());
     let mk_other_end = end::other(repo_path.clone());
     // 1. do a merge analysis
     let (analysis, _) = match repo.merge_analysis(&[&fetch_commit]) {
         Ok(x) => x,
-        Err(err) => return mk_end(end::Status::FailedMergeAnalysis, format!("{:?}", err)),
+        Err(err) => return mk_end(end::Status::FailedMergeAnalysis, format!("{:?}", err)).with_git_error(&err),
     };
 
-    // 2. Do the appropriate merge
-    if analysis.is_fast_forward() {
+    // 2. Do the appropriate merge. Rebasing onto a branch that is only ahead
+    // is a fast-forward too, and git reports and hooks it as one.
+    let no_ff = strategy.ff == pull::FastForward::Never && !strategy.rebase;
+    if analysis.is_fast_forward() && !no_ff {
+        details.strategy = Some("fast_forward");
         let refname = format!("refs/heads/{}", remote_branch);
         match repo.find_reference(&refname) {
-            Ok(mut r) => return fast_forward(repo, &mut r, &fetch_commit, repo_path),
+            Ok(mut r) => return fast_forward(repo, &mut r, &fetch_commit, repo_path, progress, details),
             Err(_) => {
                 // The branch doesn't exist so just set the reference to the
                 // commit directly. Usually this is because you are
@@ -258,7 +599,7 @@ fn do_merge<'a>(
                     return mk_other_end(format!("Unable to set head\n    {}", err))
                 };
                 return match repo.checkout_head(Some(
-                        git2::build::CheckoutBuilder::default()
+                        checkout_opts(progress, &repo_path)
                             .allow_conflicts(true)
                             .conflict_style_merge(true)
                             .force(),
@@ -268,8 +609,20 @@ fn do_merge<'a>(
                 };
             }
         }
-    } else if analysis.is_normal() {
+    } else if analysis.is_normal() || analysis.is_fast_forward() {
+        if strategy.rebase {
+            details.strategy = Some("rebase");
+            return rebase(repo, &fetch_commit, repo_path, progress, details)
+        }
+        if strategy.ff == pull::FastForward::Only {
+            return mk_end(end::Status::NotFastForward, format!(
+                "Not possible to fast-forward, {} local and {} incoming commits",
+                details.ahead.unwrap_or(0),
+                details.behind.unwrap_or(0),
+            ))
+        }
         // do a normal merge
+        details.strategy = Some("merge");
         let reference = match repo.head() {
             Ok(x) => x,
             Err(err) => return mk_other_end(
@@ -280,7 +633,7 @@ fn do_merge<'a>(
             Ok(x) => x,
             Err(err) => return mk_other_end(format!("unable to resolve reference\n    {}", err)),
         };
-        return normal_merge(&repo, &head_commit, &fetch_commit, repo_path)
+        return normal_merge(repo, &head_commit, &fetch_commit, repo_path, progress, details)
     }
 
     return if analysis.is_none() {
@@ -368,7 +721,120 @@ fn check_repo_dirty(repo: &Repository) -> Option<Vec<String>> {
     }
 }
 
-fn run(repo_path: String, shared_data: SharedData) -> end::End {
+fn run(repo_path: String, ctx: &RunContext) -> end::End {
+    ctx.progress.start(&repo_path);
+    let started = Instant::now();
+    let mut details = end::Details::default();
+    let end = update(repo_path.clone(), ctx, &mut details);
+    end.with_details(details).with_duration(started.elapsed())
+}
+
+fn needs_post_update(end: &end::End, ctx: &RunContext) -> bool {
+    end.status() == &end::Status::Updated && !ctx.post_update.is_empty() && !ctx.cancel.is_cancelled()
+}
+
+// Runs once the fetch pool has let go of the repo, so commands waiting for a
+// --run-jobs slot hold up neither a worker nor a host slot. The repo counts as
+// updating until then.
+fn post_update(end: end::End, ctx: &RunContext) -> end::End {
+    if !needs_post_update(&end, ctx) {
+        ctx.watchdog.done(end.path());
+        return end;
+    }
+    let started = Instant::now();
+    let commands = ctx.post_update.run(end.path(), end.details(), |command| ctx.progress.running(end.path(), command));
+    let failed = commands.iter().find(|x| !x.succeeded()).cloned();
+    let end = end.with_commands(commands, started.elapsed());
+    ctx.watchdog.done(end.path());
+    match failed {
+        Some(failed) => end.with_failed_run(end::Status::CommandFailed, &failed),
+        None => end,
+    }
+}
+
+// Commits reachable from the new upstream but not from the old HEAD. Only the
+// first `limit` are kept; the rest are just counted.
+fn incoming_commits(repo: &Repository, old: git2::Oid, new: git2::Oid, limit: usize) -> (Vec<end::Commit>, usize) {
+    // The list is turned off, so there is nothing to count either.
+    if limit == 0 {
+        return (vec![], 0);
+    }
+    let mut walk = match repo.revwalk() {
+        Ok(x) => x,
+        Err(_) => return (vec![], 0),
+    };
+    let started = walk.push(new)
+        .and_then(|_| walk.hide(old))
+        .and_then(|_| walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME));
+    if started.is_err() {
+        return (vec![], 0);
+    }
+    let mut commits = vec![];
+    let mut not_shown = 0;
+    for oid in walk.filter_map(|x| x.ok()) {
+        if commits.len() >= limit {
+            not_shown += 1;
+            continue;
+        }
+        if let Ok(commit) = repo.find_commit(oid) {
+            let short = commit.as_object().short_id().ok().and_then(|x| x.as_str().map(String::from));
+            commits.push(end::Commit {
+                oid: short.unwrap_or_else(|| oid.to_string()),
+                author: commit.author().name().unwrap_or("unknown").to_string(),
+                subject: commit.summary().unwrap_or("").to_string(),
+            });
+        }
+    }
+    (commits, not_shown)
+}
+
+// Runs the hooks `git pull` would have. Merging into an existing branch runs
+// post-merge. A rebase checks out the fetched commit and then rewrites the
+// local ones onto it, and a branch that did not exist yet was checked out, as
+// in a clone. Stops at the first hook that fails.
+fn run_hooks(
+    repo: &Repository,
+    old_head: Option<git2::Oid>,
+    upstream: git2::Oid,
+    details: &end::Details,
+    progress: &progress::Progress,
+    repo_path: &str,
+) -> Vec<end::CommandRun> {
+    let checkout = |old: git2::Oid, new: String| vec![old.to_string(), new, String::from("1")];
+    let mut to_run: Vec<(&str, Vec<String>, String)> = vec![];
+    match (old_head, &details.new_head, details.strategy) {
+        (Some(old_head), _, Some("rebase")) => {
+            to_run.push(("post-checkout", checkout(old_head, upstream.to_string()), String::new()));
+            if !details.rebased.is_empty() {
+                let rewritten = details.rebased.iter().map(|x| format!("{} {}\n", x.old, x.new)).collect();
+                to_run.push(("post-rewrite", vec![String::from("rebase")], rewritten));
+            }
+        },
+        (Some(_), _, _) => to_run.push(("post-merge", vec![String::from("0")], String::new())),
+        (None, Some(new_head), _) => to_run.push(("post-checkout", checkout(git2::Oid::zero(), new_head.clone()), String::new())),
+        (None, None, _) => {},
+    };
+    let mut runs = vec![];
+    for (name, args, input) in to_run {
+        progress.running(repo_path, &format!("{} hook", name));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        if let Some(run) = hooks::run(repo, name, &args, &input) {
+            let failed = !run.succeeded();
+            runs.push(run);
+            if failed {
+                break;
+            }
+        }
+    }
+    runs
+}
+
+fn head_oid(repo: &Repository) -> Option<git2::Oid> {
+    repo.head().ok().and_then(|x| x.target())
+}
+
+// Fills in details as it learns them, so early endings still carry what was found.
+fn update(repo_path: String, ctx: &RunContext, details: &mut end::Details) -> end::End {
     let mk_end = end::with_path(repo_path.clone());
     let mk_other_end = end::other(repo_path.clone());
 
@@ -385,81 +851,254 @@ fn run(repo_path: String, shared_data: SharedData) -> end::End {
         },
         Err(err) => return mk_other_end(format!("Can't get local head name, {}", err)),
     };
+    details.branch = Some(remote_branch.clone());
+    let old_head = head_oid(&repo);
+    details.old_head = old_head.map(|x| x.to_string());
     let mut remote = match get_origin_remote(&repo, repo_path.clone()) {
         Ok(r) => r,
         Err(end) => return end,
     };
+    let urls = url_rewrite::remote_urls(&repo, &remote);
+    details.remote = urls.as_ref().map(|x| x.0.clone()).or_else(|| remote.url().map(String::from));
+    details.push_remote = urls.map(|x| x.1);
+    details.identity = details.remote.as_deref().and_then(creds::identity);
+    let strategy = match repo.config() {
+        Ok(config) => ctx.pull.strategy(&config, &remote_branch, remote.name().unwrap_or("origin")),
+        Err(err) => return mk_other_end(format!("Unable to read git config\n    {}", err)).with_git_error(&err),
+    };
+    // libgit2 applies insteadOf itself, not always as git does. When it got a
+    // different url, fetch from git's into the same tracking branches.
+    let mut refspecs = None;
+    if let Some(url) = details.remote.clone().filter(|x| Some(x.as_str()) != remote.url()) {
+        let specs: Vec<String> = remote.fetch_refspecs()
+            .map(|x| x.iter().flatten().map(String::from).collect())
+            .unwrap_or_default();
+        remote = match url_rewrite::anonymous_remote(&repo, &url) {
+            Ok(x) => x,
+            Err(err) => return mk_other_end(format!("Unable to use remote url {}\n    {}", url, err)).with_git_error(&err),
+        };
+        refspecs = Some(specs);
+    }
 
     if let Some(statuses) = check_repo_dirty(&repo) {
          return mk_end(end::Status::Dirty, statuses.join("\n    "))
     };
 
-    // Up to here, no network calls are made
-    let fetch_commit = match do_fetch(&repo, &[&remote_branch], &mut remote, &remote_branch, shared_data, &repo_path) {
+    // Up to here, no network calls are made. Pruning compares against the
+    // remote's own refspecs, so it fetches with those instead of just the branch.
+    let refs: Vec<&str> = match &refspecs {
+        Some(specs) => specs.iter().map(String::as_str).collect(),
+        None if strategy.prune => vec![],
+        None => vec![&remote_branch],
+    };
+    let (fetch_commit, attempts) = match do_fetch(&repo, &refs, &mut remote, &remote_branch, strategy.prune, ctx, &repo_path) {
         Ok(x) => x,
-        Err(err) => return mk_end(end::Status::FailedFetch, format!("{:?}", err)),
+        Err(end) => return end,
     };
-    return do_merge(&repo, &remote_branch, fetch_commit, repo_path)
+    if let Some(Ok((ahead, behind))) = old_head.map(|x| repo.graph_ahead_behind(x, fetch_commit.id())) {
+        details.ahead = Some(ahead);
+        details.behind = Some(behind);
+    }
+    // Merging and checking out are never interrupted, so only stop before them.
+    if !ctx.watchdog.fetched(&repo_path) {
+        return mk_end(end::Status::TimedOut, String::from("Gave up on the fetch")).with_attempts(attempts);
+    }
+    if ctx.cancel.is_cancelled() {
+        return mk_end(end::Status::Cancelled, String::from("Fetched, but cancelled before merging")).with_attempts(attempts);
+    }
+    let upstream = fetch_commit.id();
+    let end = do_merge(&repo, &remote_branch, fetch_commit, repo_path, strategy, &ctx.progress, details).with_attempts(attempts);
+    details.new_head = head_oid(&repo).map(|x| x.to_string());
+    if let (end::Status::Updated, Some(old_head)) = (end.status(), old_head) {
+        let (commits, not_shown) = incoming_commits(&repo, old_head, upstream, ctx.log_limit);
+        details.commits = commits;
+        details.commits_not_shown = not_shown;
+    }
+    if !ctx.watch.is_empty() {
+        details.attention = ctx.watch.touched(end.path(), &details.changes);
+    }
+    if end.status() != &end::Status::Updated {
+        return end;
+    }
+    // Like post-update commands, hooks are skipped once the run is cancelled.
+    if ctx.hooks && !ctx.cancel.is_cancelled() {
+        details.hooks = run_hooks(&repo, old_head, upstream, details, &ctx.progress, end.path());
+        if let Some(failed) = details.hooks.iter().find(|x| !x.succeeded()) {
+            return end.with_failed_run(end::Status::HookFailed, failed);
+        }
+    }
+    end
 }
 
-#[tokio::main]
-async fn main() {
-    let config = config::new();
-    let shared_data: SharedData = Arc::new(Mutex::new(creds::Storage::from_config(&config)));
-
-    for gd in config.git_dirs {
-        print!("\nUpgitting {}:", gd);
-        io::stdout().flush().expect("Could not flush stdout");
-        let (tx, rx) = mpsc::channel();
-
-        let (mut non_repo_ends, repos) = fs::read_dir(&gd)
-            .expect(format!("could not read dir {}", &gd).as_str())
-            .fold((Vec::new(), Vec::new()), |(mut ends, mut repos), fs_entry| {
-                match fs_entry {
-                    Ok(repo) => {
-                        let repo_path = repo.path().display().to_string();
-                        if repo.metadata().expect(format!("could not get repo metadata for {}", repo_path).as_str()).is_dir() {
-                            repos.push(repo_path);
-                        } else {
-                            ends.push(end::non_repo(repo_path, format!("")));
-                        }
-                    },
-                    Err(err) => {
-                        ends.push(end::non_repo(
-                                String::from("Unknown fs entity"),
-                                format!("{:?}", err)
-                        ));
+fn list_repos(git_dir: &String) -> (Vec<end::End>, Vec<String>) {
+    let entries = fs::read_dir(git_dir).unwrap_or_else(|err| {
+        eprintln!("Could not read dir {}: {}", git_dir, err);
+        std::process::exit(1);
+    });
+    entries
+        .fold((Vec::new(), Vec::new()), |(mut ends, mut repos), fs_entry| {
+            match fs_entry {
+                Ok(repo) => {
+                    let repo_path = repo.path().display().to_string();
+                    let metadata = repo.metadata().unwrap_or_else(|err| {
+                        eprintln!("Could not get repo metadata for {}: {}", repo_path, err);
+                        std::process::exit(1);
+                    });
+                    if metadata.is_dir() {
+                        repos.push(repo_path);
+                    } else {
+                        ends.push(end::non_repo(repo_path, format!("")));
                     }
-                };
-                (ends, repos)
-            });
-        let mut counter = non_repo_ends.len();
+                },
+                Err(err) => {
+                    ends.push(end::non_repo(
+                            String::from("Unknown fs entity"),
+                            format!("{:?}", err)
+                    ));
+                }
+            };
+            (ends, repos)
+        })
+}
 
-        let num_repos = counter + repos.len();
+// Only used to apply per host limits, so any failure just means no limit.
+fn remote_host(repo_path: &str) -> Option<String> {
+    let repo = Repository::open(repo_path).ok()?;
+    let remote = get_origin_remote(&repo, repo_path.to_string()).ok()?;
+    let url = url_rewrite::remote_urls(&repo, &remote).map(|x| x.0);
+    creds::remote_domain(url.as_deref().or(remote.url())?)
+}
 
-        for r in repos {
-            let tx_clone = mpsc::Sender::clone(&tx);
-            let shared_data_clone = Arc::clone(&shared_data);
+fn main() {
+    let config = config::new();
+    let cancel = cancel::on_ctrl_c();
+    let started = Instant::now();
+    let json = config.format == end::Format::Json;
+    let label = config.git_dirs.join(", ");
+    if !json {
+        println!();
+    }
+    let progress = progress::Progress::new(label, 0, json);
+    let watchdog = watchdog::Watchdog::new(config.fetch_timeout);
+    let ctx = Arc::new(RunContext {
+        broker: broker::Broker::spawn(creds::Storage::from_config(&config), progress.clone(), cancel.clone()),
+        known_hosts: known_hosts::KnownHosts::load(config.host_key_check.clone()),
+        fetch_timeout: config.fetch_timeout,
+        watchdog: watchdog.clone(),
+        retries: config.retries,
+        log_limit: config.log_limit,
+        watch: config.watch.clone(),
+        post_update: config.post_update.clone(),
+        hooks: config.hooks,
+        pull: config.pull.clone(),
+        cancel: cancel.clone(),
+        progress: progress.clone(),
+    });
 
-            tokio::spawn(async move {
-                let end = run(r, Arc::clone(&shared_data_clone));
-                tx_clone.send(end).expect("expected to be able to send value");
-            });
+    // Every git dir feeds the same queue, so --jobs bounds the whole run.
+    let mut ends = vec![];
+    let mut repos = vec![];
+    for gd in config.git_dirs.iter() {
+        let (mut dir_ends, mut dir_repos) = list_repos(gd);
+        ends.append(&mut dir_ends);
+        repos.append(&mut dir_repos);
+    }
+
+    let previous = match config.rerun {
+        state::Rerun::All => None,
+        _ => state::load(&config.state_file),
+    };
+    if config.rerun != state::Rerun::All && previous.is_none() {
+        eprintln!("\nNo earlier run found in {}, updating every repo", config.state_file);
+    }
+    let plan = state::plan(&config.rerun, previous, repos);
+    let repos = plan.run;
+    // Results that still stand were already reported, so they are not counted again.
+    let kept_paths: HashSet<String> = plan.kept.iter().map(|x| x.path().to_string()).collect();
+    ends.retain(|x| !kept_paths.contains(x.path()));
+    let mut recorder = state::Recorder::new(config.state_file.clone(), plan.kept.clone());
+    for end in ends.iter() {
+        recorder.record(end);
+    }
+    progress.set_total(ends.len() + repos.len(), ends.len());
+    progress.spawn_redraw();
+    // A resumed run reports on the whole run, not just the part done now.
+    if config.rerun == state::Rerun::Resume {
+        ends.extend(plan.kept);
+    }
+
+    let mut unfinished: HashSet<String> = repos.iter().cloned().collect();
+    let jobs = repos.into_iter().map(|repo_path| pool::Job {
+        host: if config.host_jobs.is_empty() { None } else { remote_host(&repo_path) },
+        repo_path,
+    }).collect();
+
+    let stalled_watchdog = watchdog.clone();
+    let (run_ctx, slow_ctx) = (Arc::clone(&ctx), Arc::clone(&ctx));
+    let rx = pool::run(jobs, config.jobs, config.host_jobs.clone(), cancel.clone(), move |r| run(r, &run_ctx), move |r| stalled_watchdog.stalled(r));
+    let rx = pool::then(rx, move |x| needs_post_update(x, &slow_ctx), move |x| post_update(x, &ctx));
+
+    // A connection can hang before any progress callback gets a chance to
+    // abort it, so the run deadline is enforced here instead.
+    let mut deadline = config.run_timeout.map(|x| Instant::now() + x);
+    while !unfinished.is_empty() {
+        let received = match deadline {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        let end = match received {
+            Ok(end) => end,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let msg = format!(
+                    "Run took longer than {} seconds",
+                    config.run_timeout.map(|x| x.as_secs()).unwrap_or(0),
+                );
+                // Repos that are fetching can be left where they are. Those
+                // already changing their working tree are waited for.
+                let updating: HashSet<String> = watchdog.stop().into_iter().collect();
+                let mut timed_out: Vec<_> = unfinished.difference(&updating).cloned().collect();
+                timed_out.sort();
+                for repo_path in timed_out {
+                    let end = end::with_path(repo_path)(end::Status::TimedOut, msg.clone());
+                    progress.finish(&end);
+                    recorder.record(&end);
+                    ends.push(end);
+                }
+                unfinished.retain(|x| updating.contains(x));
+                deadline = None;
+                continue;
+            },
+        };
+        // Repos given up on at the deadline may still report in.
+        if !unfinished.remove(end.path()) {
+            continue;
         }
-        drop(tx);
-
-        let mut ends = vec![];
-        for end in rx {
-            counter += 1;
-            // Do not output here if arc structure is being interacted with,
-            // as it might mean user is being promted for input.
-            if let Ok(_) = shared_data.try_lock() {
-                print!("\rUpgitting {}: {} of {}", gd, counter, num_repos);
-                io::stdout().flush().expect("Could not flush stdout");
-            }
-            ends.push(end);
+        progress.finish(&end);
+        recorder.record(&end);
+        ends.push(end);
+    };
+    // Workers stop picking up repos once cancelled, so these never started.
+    // Without a cancel, that would be a bug in upgit.
+    let mut not_started: Vec<_> = unfinished.drain().collect();
+    not_started.sort();
+    for repo_path in not_started {
+        let end = if cancel.is_cancelled() {
+            end::with_path(repo_path)(end::Status::Cancelled, String::from("Not started"))
+        } else {
+            end::other(repo_path)(String::from("Not started"))
         };
-        ends.append(&mut non_repo_ends);
-        end::print(&ends);
+        recorder.record(&end);
+        ends.push(end);
+    }
+    recorder.finish(!cancel.is_cancelled());
+    progress.close();
+    match config.format {
+        end::Format::Text => end::print(&ends),
+        end::Format::Json => end::print_json(&ends, &config.git_dirs, started.elapsed(), cancel.is_cancelled()),
+    }
+    if cancel.is_cancelled() {
+        std::process::exit(130);
     }
+    std::process::exit(end::outcome(&ends, config.fail_on.as_ref()).exit_code());
 }
@@ -0,0 +1,367 @@
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::cancel;
+use crate::end;
+
+pub struct Job {
+    pub repo_path: String,
+    pub host: Option<String>,
+}
+
+// Maximum repos running at once per host. Hosts not listed use `default`,
+// and None means only the worker count limits them.
+#[derive(Debug, Clone, Default)]
+pub struct HostLimits {
+    pub per_host: HashMap<String, usize>,
+    pub default: Option<usize>,
+}
+
+impl HostLimits {
+    fn limit(&self, host: &str) -> Option<usize> {
+        self.per_host.get(host).cloned().or(self.default)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.per_host.is_empty() && self.default.is_none()
+    }
+}
+
+struct State {
+    queue: VecDeque<Job>,
+    running: HashMap<String, usize>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    limits: HostLimits,
+    cancel: cancel::Cancel,
+}
+
+impl Shared {
+    // Takes the first queued job whose host has room, waiting for a running job
+    // to finish if every queued job is held back by its host limit. Once the
+    // run is cancelled, queued jobs are left where they are.
+    fn next(&self) -> Option<Job> {
+        let mut state = self.state.lock().expect("could not acquire queue lock");
+        loop {
+            if state.queue.is_empty() || self.cancel.is_cancelled() {
+                return None;
+            }
+            let running = &state.running;
+            let limits = &self.limits;
+            let ready = state.queue.iter().position(|job| match &job.host {
+                Some(host) => match limits.limit(host) {
+                    Some(limit) => running.get(host).cloned().unwrap_or(0) < limit,
+                    None => true,
+                },
+                None => true,
+            });
+            if let Some(i) = ready {
+                let job = state.queue.remove(i).expect("index is in the queue");
+                if let Some(host) = &job.host {
+                    *state.running.entry(host.clone()).or_insert(0) += 1;
+                }
+                return Some(job);
+            }
+            state = self.changed.wait(state).expect("could not acquire queue lock");
+        }
+    }
+
+    fn finish(&self, job: &Job) {
+        if let Some(host) = &job.host {
+            let mut state = self.state.lock().expect("could not acquire queue lock");
+            if let Some(count) = state.running.get_mut(host) {
+                *count -= 1;
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+// How often a worker asks whether the repo it runs has stalled.
+const CHECK_EVERY: Duration = Duration::from_millis(250);
+
+// A fixed number of worker threads that pull repos off one shared queue.
+// libgit2 calls block, so each repo gets a whole thread while it runs, and at
+// most `workers` repos are fetching at any time. Host limits only hold back
+// repos on that host; the other workers keep going. When `stalled` gives an
+// end for a repo, its worker reports that and moves on without it.
+pub fn run<F, G>(
+    jobs: Vec<Job>,
+    workers: usize,
+    limits: HostLimits,
+    cancel: cancel::Cancel,
+    work: F,
+    stalled: G,
+) -> mpsc::Receiver<end::End>
+where
+    F: Fn(String) -> end::End + Send + Sync + 'static,
+    G: Fn(&str) -> Option<end::End> + Send + Sync + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: jobs.into_iter().collect(),
+            running: HashMap::new(),
+        }),
+        changed: Condvar::new(),
+        limits,
+        cancel,
+    });
+    let work = Arc::new(work);
+    let stalled = Arc::new(stalled);
+
+    for _ in 0..workers.max(1) {
+        let tx = tx.clone();
+        let shared = Arc::clone(&shared);
+        let work = Arc::clone(&work);
+        let stalled = Arc::clone(&stalled);
+        thread::spawn(move || {
+            while let Some(job) = shared.next() {
+                let end = supervise(&work, &*stalled, &job);
+                shared.finish(&job);
+                if tx.send(end).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    rx
+}
+
+// A repo that panics still gets an End, and still frees its host slot, so
+// neither the other workers nor the summary wait for it forever.
+fn run_job<F>(work: &F, repo_path: String) -> end::End
+where
+    F: Fn(String) -> end::End,
+{
+    panic::catch_unwind(AssertUnwindSafe(|| work(repo_path.clone()))).unwrap_or_else(|panic| {
+        let reason = panic.downcast_ref::<&str>().map(|x| x.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown panic"));
+        end::other(repo_path)(format!("Upgit crashed updating this repo: {}", reason))
+    })
+}
+
+// Runs the job on a thread of its own, so one that is stuck where it can not
+// be interrupted can be left behind.
+fn supervise<F, G>(work: &Arc<F>, stalled: &G, job: &Job) -> end::End
+where
+    F: Fn(String) -> end::End + Send + Sync + 'static,
+    G: Fn(&str) -> Option<end::End>,
+{
+    let (done, result) = mpsc::channel();
+    let work = Arc::clone(work);
+    let repo_path = job.repo_path.clone();
+    thread::spawn(move || {
+        // Nobody is listening any more if the job was left behind.
+        let _ = done.send(run_job(&*work, repo_path));
+    });
+    loop {
+        match result.recv_timeout(CHECK_EVERY) {
+            Ok(end) => return end,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if let Some(end) = stalled(&job.repo_path) {
+                    return end;
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return end::other(job.repo_path.clone())(String::from("Upgit lost track of this repo"));
+            },
+        }
+    }
+}
+
+// Passes each end the pool gives back through `then`. Those `slow` picks, like
+// repos with post-update commands to run, get a thread of their own, as the
+// worker and host slot they came from are already free for the next repo.
+pub fn then<S, F>(ends: mpsc::Receiver<end::End>, slow: S, then: F) -> mpsc::Receiver<end::End>
+where
+    S: Fn(&end::End) -> bool + Send + 'static,
+    F: Fn(end::End) -> end::End + Send + Sync + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let then = Arc::new(then);
+    thread::spawn(move || {
+        for end in ends {
+            if !slow(&end) {
+                if tx.send(then(end)).is_err() {
+                    return;
+                }
+                continue;
+            }
+            let tx = tx.clone();
+            let then = Arc::clone(&then);
+            thread::spawn(move || {
+                let repo_path = end.path().to_string();
+                let _ = tx.send(run_job(&|_| then(end.clone()), repo_path));
+            });
+        }
+    });
+    rx
+}
+
+pub fn default_workers() -> usize {
+    thread::available_parallelism().map(|x| x.get()).unwrap_or(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn jobs(count: usize, host: Option<&str>) -> Vec<Job> {
+        (0..count).map(|x| Job {
+            repo_path: format!("/{}/{}", host.unwrap_or("local"), x),
+            host: host.map(String::from),
+        }).collect()
+    }
+
+    fn up_to_date(path: String) -> end::End {
+        end::with_path(path)(end::Status::UpToDate, String::from(""))
+    }
+
+    // Runs the jobs and reports the most that were ever running at once for `host`.
+    fn most_at_once(jobs: Vec<Job>, workers: usize, limits: HostLimits, host: &'static str) -> usize {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (running_clone, most_clone) = (Arc::clone(&running), Arc::clone(&most));
+        let count = jobs.len();
+        let rx = run(jobs, workers, limits, cancel::Cancel::default(), move |path| {
+            let counted = path.starts_with(&format!("/{}/", host));
+            if counted {
+                let now = running_clone.fetch_add(1, Ordering::SeqCst) + 1;
+                most_clone.fetch_max(now, Ordering::SeqCst);
+            }
+            thread::sleep(Duration::from_millis(5));
+            if counted {
+                running_clone.fetch_sub(1, Ordering::SeqCst);
+            }
+            up_to_date(path)
+        }, |_| None);
+        assert_eq!(rx.iter().count(), count);
+        most.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn runs_every_job_once() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_clone = Arc::clone(&seen);
+        let rx = run(jobs(50, None), 4, HostLimits::default(), cancel::Cancel::default(), move |path| {
+            seen_clone.lock().unwrap().push(path.clone());
+            up_to_date(path)
+        }, |_| None);
+        assert_eq!(rx.iter().count(), 50);
+        let mut done = seen.lock().unwrap().clone();
+        done.sort();
+        let mut expected: Vec<_> = jobs(50, None).into_iter().map(|x| x.repo_path).collect();
+        expected.sort();
+        assert_eq!(done, expected);
+    }
+
+    #[test]
+    fn a_panicking_job_still_ends() {
+        let mut limits = HostLimits::default();
+        limits.per_host.insert(String::from("a.com"), 1);
+        let rx = run(jobs(5, Some("a.com")), 2, limits, cancel::Cancel::default(), move |path| {
+            if path == "/a.com/0" {
+                panic!("boom");
+            }
+            up_to_date(path)
+        }, |_| None);
+        let ends: Vec<_> = rx.iter().collect();
+        assert_eq!(ends.len(), 5);
+        let crashed = ends.iter().find(|x| x.path() == "/a.com/0").unwrap();
+        assert_eq!(crashed.status(), &end::Status::WIPOther);
+        assert!(crashed.report().ends_with("boom"));
+    }
+
+    #[test]
+    fn leaves_stalled_jobs_behind() {
+        let rx = run(jobs(3, None), 1, HostLimits::default(), cancel::Cancel::default(), move |path| {
+            if path == "/local/0" {
+                thread::sleep(Duration::from_secs(60));
+            }
+            up_to_date(path)
+        }, |path| match path {
+            "/local/0" => Some(end::with_path(path.to_string())(end::Status::TimedOut, String::from("stalled"))),
+            _ => None,
+        });
+        let ends: Vec<_> = rx.iter().collect();
+        assert_eq!(ends.len(), 3);
+        assert_eq!(ends.iter().filter(|x| x.status() == &end::Status::TimedOut).count(), 1);
+    }
+
+    #[test]
+    fn stops_taking_jobs_when_cancelled() {
+        let cancel = cancel::Cancel::default();
+        let cancel_clone = cancel.clone();
+        let rx = run(jobs(50, None), 2, HostLimits::default(), cancel, move |path| {
+            cancel_clone.cancel();
+            up_to_date(path)
+        }, |_| None);
+        // Each worker finishes the job it already had.
+        assert!(rx.iter().count() <= 2);
+    }
+
+    #[test]
+    fn slow_ends_do_not_hold_up_workers() {
+        let (last_done, wait_for_last) = mpsc::channel();
+        let last_done = Mutex::new(last_done);
+        let rx = run(jobs(3, None), 1, HostLimits::default(), cancel::Cancel::default(), move |path| {
+            if path == "/local/2" {
+                last_done.lock().unwrap().send(()).unwrap();
+            }
+            up_to_date(path)
+        }, |_| None);
+        // With the only worker busy with it, the last job could never run.
+        let wait_for_last = Mutex::new(wait_for_last);
+        let rx = then(rx, |x| x.path() == "/local/0", move |end| {
+            if end.path() != "/local/0" {
+                return end;
+            }
+            match wait_for_last.lock().unwrap().recv_timeout(Duration::from_secs(5)) {
+                Ok(()) => end,
+                Err(_) => end::with_path(end.path().to_string())(end::Status::TimedOut, String::from("held up")),
+            }
+        });
+        let ends: Vec<_> = rx.iter().collect();
+        assert_eq!(ends.len(), 3);
+        assert!(ends.iter().all(|x| x.status() == &end::Status::UpToDate));
+    }
+
+    #[test]
+    fn never_exceeds_workers() {
+        assert!(most_at_once(jobs(20, Some("a.com")), 3, HostLimits::default(), "a.com") <= 3);
+    }
+
+    #[test]
+    fn never_exceeds_host_limit() {
+        let mut limits = HostLimits::default();
+        limits.per_host.insert(String::from("a.com"), 2);
+        let mut all = jobs(20, Some("a.com"));
+        all.append(&mut jobs(20, Some("b.com")));
+        assert!(most_at_once(all, 8, limits, "a.com") <= 2);
+    }
+
+    #[test]
+    fn other_hosts_keep_full_parallelism() {
+        let mut limits = HostLimits::default();
+        limits.per_host.insert(String::from("a.com"), 1);
+        let mut all = jobs(20, Some("a.com"));
+        all.append(&mut jobs(20, Some("b.com")));
+        assert!(most_at_once(all, 6, limits, "b.com") > 1);
+    }
+
+    #[test]
+    fn default_limit_applies_to_unlisted_hosts() {
+        let limits = HostLimits { per_host: HashMap::new(), default: Some(1) };
+        assert_eq!(most_at_once(jobs(10, Some("c.com")), 4, limits, "c.com"), 1);
+    }
+}
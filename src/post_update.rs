@@ -0,0 +1,238 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+use crate::end;
+use crate::watch;
+
+// Lines of output kept from each command for the report.
+const TAIL_LINES: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    repo: String,
+    paths: Option<String>, // only run when a changed file matches
+    command: String,
+}
+
+// Commands to run in a repo after it updated, e.g. `cargo build`. They can be
+// heavy, so at most `jobs` run at once no matter how many workers there are.
+#[derive(Debug, Clone)]
+pub struct PostUpdate {
+    rules: Vec<Rule>,
+    jobs: usize,
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+// Waits for a free slot, and gives it back when dropped.
+struct Slot<'a>(&'a (Mutex<usize>, Condvar));
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let (running, freed) = self.0;
+        *running.lock().expect("could not acquire command slots lock") -= 1;
+        freed.notify_one();
+    }
+}
+
+// <repo>[@<paths>]=<command>, with `*` as the repo for every repo. The repo
+// part is always there, as a command can contain `=` itself, e.g.
+// `*=FOO=1 make`, and guessing where one ends would run the wrong thing.
+fn parse_rule(entry: &str) -> Result<Rule, String> {
+    let (target, command) = match entry.split_once('=') {
+        Some((target, command)) if !target.trim().is_empty() && !command.trim().is_empty() => (target.trim(), command),
+        _ => return Err(format!("--run expects <repo>=<command>, with `*=` for every repo, got \"{}\"", entry)),
+    };
+    let (repo, paths) = match target.split_once('@') {
+        Some((repo, paths)) => (repo, Some(paths.to_string())),
+        None => (target, None),
+    };
+    Ok(Rule {
+        repo: repo.to_string(),
+        paths,
+        command: command.trim().to_string(),
+    })
+}
+
+fn tail(output: &str, lines: usize) -> String {
+    let all: Vec<_> = output.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    }
+}
+
+// Runs to completion with the given input, keeping the tail of stdout and
+// stderr for the report.
+pub fn capture(mut cmd: Command, command: &str, input: &str) -> end::CommandRun {
+    let began = Instant::now();
+    let child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+    let output = child.and_then(|mut child| {
+        // Written from its own thread, since a command that fills its output
+        // pipes before reading all its input would otherwise never finish.
+        let writer = child.stdin.take().map(|mut stdin| {
+            let input = input.to_string();
+            // A command that exits without reading its input is fine.
+            std::thread::spawn(move || { let _ = stdin.write_all(input.as_bytes()); })
+        });
+        let output = child.wait_with_output();
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+        output
+    });
+    match output {
+        Ok(output) => {
+            let text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+            end::CommandRun {
+                command: command.to_string(),
+                exit_code: output.status.code(),
+                output_tail: tail(&text, TAIL_LINES),
+                duration_ms: began.elapsed().as_millis(),
+            }
+        },
+        Err(err) => end::CommandRun {
+            command: command.to_string(),
+            exit_code: None,
+            output_tail: format!("Could not start: {}", err),
+            duration_ms: began.elapsed().as_millis(),
+        },
+    }
+}
+
+impl PostUpdate {
+    pub fn parse<'a>(entries: impl Iterator<Item = &'a str>, jobs: usize) -> Result<PostUpdate, String> {
+        Ok(PostUpdate {
+            rules: entries.map(str::trim).filter(|x| !x.is_empty()).map(parse_rule).collect::<Result<_, _>>()?,
+            jobs: jobs.max(1),
+            running: Arc::new((Mutex::new(0), Condvar::new())),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn commands_for(&self, repo_path: &str, changes: &[end::Change]) -> Vec<&str> {
+        self.rules.iter().filter(|rule| {
+            let touched = |paths: &String| changes.iter().any(|change| {
+                std::iter::once(&change.path).chain(change.old_path.iter())
+                    .any(|file| paths.split(',').any(|x| watch::pattern_matches(x, file)))
+            });
+            watch::repo_matches(&rule.repo, repo_path) && rule.paths.as_ref().is_none_or(touched)
+        }).map(|x| x.command.as_str()).collect()
+    }
+
+    fn slot(&self) -> Slot<'_> {
+        let (running, freed) = &*self.running;
+        let mut count = running.lock().expect("could not acquire command slots lock");
+        while *count >= self.jobs {
+            count = freed.wait(count).expect("could not acquire command slots lock");
+        }
+        *count += 1;
+        Slot(&self.running)
+    }
+
+    // Runs each matching command in order, stopping at the first that fails.
+    pub fn run(&self, repo_path: &str, details: &end::Details, mut started: impl FnMut(&str)) -> Vec<end::CommandRun> {
+        let mut runs = vec![];
+        for command in self.commands_for(repo_path, &details.changes) {
+            let _slot = self.slot();
+            started(command);
+            let mut cmd = shell(command);
+            cmd.current_dir(repo_path)
+                .env("UPGIT_REPO", repo_path)
+                .env("UPGIT_OLD_HEAD", details.old_head.as_deref().unwrap_or(""))
+                .env("UPGIT_NEW_HEAD", details.new_head.as_deref().unwrap_or(""));
+            let run = capture(cmd, command, "");
+            let failed = !run.succeeded();
+            runs.push(run);
+            if failed {
+                break;
+            }
+        }
+        runs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(path: &str) -> end::Change {
+        end::Change {
+            change: "modified",
+            path: path.to_string(),
+            old_path: None,
+            insertions: 1,
+            deletions: 0,
+            binary: false,
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(parse_rule("*=cargo build"), Ok(Rule { repo: String::from("*"), paths: None, command: String::from("cargo build") }));
+        assert_eq!(parse_rule("web@package-lock.json=npm ci"), Ok(Rule {
+            repo: String::from("web"),
+            paths: Some(String::from("package-lock.json")),
+            command: String::from("npm ci"),
+        }));
+        assert_eq!(parse_rule("*=FOO=1 make").unwrap().command, "FOO=1 make");
+        assert_eq!(parse_rule("*=make FOO=1").unwrap().command, "make FOO=1");
+    }
+
+    #[test]
+    fn needs_the_repo_part() {
+        assert!(parse_rule("cargo build").is_err());
+        assert!(parse_rule("=make").is_err());
+        assert!(parse_rule("api=").is_err());
+        // Read as repo `FOO`, just as written.
+        assert_eq!(parse_rule("FOO=1 make").unwrap().repo, "FOO");
+    }
+
+    #[test]
+    fn picks_commands_by_repo_and_paths() {
+        let post_update = PostUpdate::parse(vec!["api=make", "*@Cargo.lock,build.rs=cargo build", "web=npm ci"].into_iter(), 1).unwrap();
+        assert_eq!(post_update.commands_for("/g/api", &[change("src/lib.rs")]), vec!["make"]);
+        assert_eq!(post_update.commands_for("/g/api", &[change("Cargo.lock")]), vec!["make", "cargo build"]);
+        assert_eq!(post_update.commands_for("/g/other", &[change("README.md")]), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn keeps_the_tail() {
+        assert_eq!(tail("a\nb\nc\n", 2), "b\nc");
+        assert_eq!(tail("a", 5), "a");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stops_at_first_failure() {
+        let post_update = PostUpdate::parse(vec!["*=echo one", "*=echo two; exit 3", "*=echo three"].into_iter(), 1).unwrap();
+        let runs = post_update.run("/", &end::Details::default(), |_| {});
+        assert_eq!(runs.len(), 2);
+        assert!(runs[0].succeeded());
+        assert_eq!(runs[1].exit_code, Some(3));
+        assert_eq!(runs[1].output_tail, "two");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn large_input_and_output_do_not_block() {
+        // Fills the output pipes before reading any input.
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "head -c 200000 /dev/zero | tr '\\0' x; cat > /dev/null; echo done"]);
+        let run = capture(cmd, "both", &"y".repeat(200_000));
+        assert!(run.succeeded());
+        assert!(run.output_tail.ends_with("done"));
+    }
+}
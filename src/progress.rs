@@ -0,0 +1,289 @@
+use std::env;
+use std::io;
+use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use crate::end;
+
+const REDRAW_EVERY: Duration = Duration::from_millis(100);
+// Past this many running repos, the rest are summed up in one line.
+const MAX_REPO_LINES: usize = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Phase {
+    Starting,
+    Fetching { objects: usize, total_objects: usize, bytes: usize },
+    CheckingOut { files: usize, total_files: usize },
+    Running { command: String },
+}
+
+#[derive(Debug)]
+struct Screen {
+    label: String,
+    total: usize,
+    done: usize,
+    running: Vec<(String, Phase)>, // in the order they started
+    to_stderr: bool, // keeps stdout clean for machine readable output
+    tty: bool,
+    drawn: usize, // lines of the view currently on the terminal
+    paused: bool,
+    closed: bool,
+}
+
+// Shows what the run is doing. On a terminal that is a view of every running
+// repo that is redrawn in place, otherwise just a line per finished repo.
+#[derive(Debug, Clone)]
+pub struct Progress(Arc<Mutex<Screen>>);
+
+// Keeps the view off the terminal while it is held, e.g. during a prompt.
+// Repos keep reporting in the meantime, it just is not drawn.
+pub struct Pause<'a>(&'a Progress);
+
+impl Drop for Pause<'_> {
+    fn drop(&mut self) {
+        self.0.screen().paused = false;
+    }
+}
+
+fn human_bytes(bytes: usize) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn describe(phase: &Phase) -> String {
+    match phase {
+        Phase::Starting => String::from("starting"),
+        Phase::Fetching { objects, total_objects, bytes } => {
+            format!("fetching {}/{} objects, {}", objects, total_objects, human_bytes(*bytes))
+        },
+        Phase::CheckingOut { files, total_files } => format!("checking out {}/{} files", files, total_files),
+        Phase::Running { command } => format!("running `{}`", command),
+    }
+}
+
+// Lines that wrap would throw off how far up the next redraw has to go.
+fn fit(line: String, width: usize) -> String {
+    if line.chars().count() <= width {
+        return line;
+    }
+    let mut cut: String = line.chars().take(width.saturating_sub(3)).collect();
+    cut.push_str("...");
+    cut
+}
+
+fn term_width() -> usize {
+    env::var("COLUMNS").ok().and_then(|x| x.parse().ok()).filter(|x| *x > 0).unwrap_or(80)
+}
+
+impl Screen {
+    fn out(&self) -> Box<dyn Write> {
+        if self.to_stderr { Box::new(io::stderr()) } else { Box::new(io::stdout()) }
+    }
+
+    fn header(&self) -> String {
+        format!("Upgitting {}: {} of {}", self.label, self.done, self.total)
+    }
+
+    fn lines(&self, width: usize) -> Vec<String> {
+        let mut lines = vec![fit(self.header(), width)];
+        for (path, phase) in self.running.iter().take(MAX_REPO_LINES) {
+            lines.push(fit(format!("  {}: {}", path, describe(phase)), width));
+        }
+        if self.running.len() > MAX_REPO_LINES {
+            lines.push(format!("  and {} more", self.running.len() - MAX_REPO_LINES));
+        }
+        lines
+    }
+
+    fn clear(&mut self, out: &mut impl Write) {
+        if self.drawn > 1 {
+            let _ = write!(out, "\x1b[{}A", self.drawn - 1);
+        }
+        if self.drawn > 0 {
+            let _ = write!(out, "\r\x1b[J");
+        }
+        self.drawn = 0;
+    }
+
+    fn draw(&mut self) {
+        if !self.tty || self.paused || self.closed {
+            return;
+        }
+        let mut out = self.out();
+        self.clear(&mut out);
+        let lines = self.lines(term_width());
+        let _ = write!(out, "{}", lines.join("\n"));
+        let _ = out.flush();
+        self.drawn = lines.len();
+    }
+
+    fn phase(&mut self, repo_path: &str, phase: Phase) {
+        match self.running.iter_mut().find(|(path, _)| path == repo_path) {
+            Some(entry) => entry.1 = phase,
+            None => self.running.push((repo_path.to_string(), phase)),
+        }
+    }
+}
+
+impl Progress {
+    pub fn new(label: String, total: usize, to_stderr: bool) -> Progress {
+        let tty = if to_stderr { io::stderr().is_terminal() } else { io::stdout().is_terminal() };
+        let screen = Screen {
+            label,
+            total,
+            done: 0,
+            running: vec![],
+            to_stderr,
+            tty,
+            drawn: 0,
+            paused: false,
+            closed: false,
+        };
+        if !tty {
+            let _ = writeln!(screen.out(), "Upgitting {}:", screen.label);
+        }
+        Progress(Arc::new(Mutex::new(screen)))
+    }
+
+    fn screen(&self) -> MutexGuard<'_, Screen> {
+        self.0.lock().expect("could not acquire progress lock")
+    }
+
+    // Redraws on a timer rather than on every callback, which can fire
+    // thousands of times a second during a big fetch.
+    pub fn spawn_redraw(&self) {
+        if !self.screen().tty {
+            return;
+        }
+        let progress = self.clone();
+        thread::spawn(move || loop {
+            {
+                let mut screen = progress.screen();
+                if screen.closed {
+                    return;
+                }
+                screen.draw();
+            }
+            thread::sleep(REDRAW_EVERY);
+        });
+    }
+
+    pub fn set_total(&self, total: usize, done: usize) {
+        let mut screen = self.screen();
+        screen.total = total;
+        screen.done = done;
+    }
+
+    pub fn start(&self, repo_path: &str) {
+        self.screen().phase(repo_path, Phase::Starting);
+    }
+
+    pub fn fetching(&self, repo_path: &str, stats: &git2::Progress) {
+        self.screen().phase(repo_path, Phase::Fetching {
+            objects: stats.received_objects(),
+            total_objects: stats.total_objects(),
+            bytes: stats.received_bytes(),
+        });
+    }
+
+    pub fn checkout(&self, repo_path: &str, files: usize, total_files: usize) {
+        self.screen().phase(repo_path, Phase::CheckingOut { files, total_files });
+    }
+
+    pub fn running(&self, repo_path: &str, command: &str) {
+        self.screen().phase(repo_path, Phase::Running { command: command.to_string() });
+    }
+
+    pub fn finish(&self, end: &end::End) {
+        let mut screen = self.screen();
+        screen.running.retain(|(path, _)| path != end.path());
+        screen.done += 1;
+        if !screen.tty {
+            let _ = writeln!(screen.out(), "  {} of {}: {}", screen.done, screen.total, end.path());
+        }
+    }
+
+    pub fn pause(&self) -> Pause<'_> {
+        let mut screen = self.screen();
+        let mut out = screen.out();
+        screen.clear(&mut out);
+        let _ = out.flush();
+        screen.paused = true;
+        Pause(self)
+    }
+
+    // Leaves only the final count on the terminal, ready for the summary.
+    pub fn close(&self) {
+        let mut screen = self.screen();
+        if screen.tty {
+            let mut out = screen.out();
+            screen.clear(&mut out);
+            let _ = writeln!(out, "{}", screen.header());
+        }
+        screen.closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(running: usize) -> Screen {
+        Screen {
+            label: String::from("~/g"),
+            total: 20,
+            done: 3,
+            running: (0..running).map(|i| (format!("~/g/{}", i), Phase::Starting)).collect(),
+            to_stderr: false,
+            tty: false,
+            drawn: 0,
+            paused: false,
+            closed: false,
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(3 * 1024 * 1024), "3.0 MiB");
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        assert_eq!(fit(String::from("abcdefghij"), 8), "abcde...");
+        assert_eq!(fit(String::from("abc"), 8), "abc");
+    }
+
+    #[test]
+    fn updates_phase_in_place() {
+        let mut screen = screen(0);
+        screen.phase("~/g/a", Phase::Starting);
+        screen.phase("~/g/b", Phase::Starting);
+        screen.phase("~/g/a", Phase::CheckingOut { files: 1, total_files: 2 });
+        assert_eq!(screen.lines(80), vec![
+            "Upgitting ~/g: 3 of 20",
+            "  ~/g/a: checking out 1/2 files",
+            "  ~/g/b: starting",
+        ]);
+    }
+
+    #[test]
+    fn caps_repo_lines() {
+        let lines = screen(MAX_REPO_LINES + 5).lines(80);
+        assert_eq!(lines.len(), MAX_REPO_LINES + 2);
+        assert_eq!(lines.last().unwrap(), "  and 5 more");
+    }
+}
@@ -0,0 +1,134 @@
+// How `git pull` would bring a branch up to date, from the repo's own config
+// and upgit's flags, which win over it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastForward {
+    Allow, // fast-forward when possible, merge otherwise
+    Only, // refuse to merge or rebase diverged branches
+    Never, // always make a merge commit
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Strategy {
+    pub rebase: bool,
+    pub ff: FastForward,
+    pub prune: bool,
+}
+
+// Settings given to upgit. Anything left as None comes from each repo's config.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub rebase: Option<bool>,
+    pub ff: Option<FastForward>,
+    pub prune: Option<bool>,
+}
+
+// Git's spellings of a boolean.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
+// `merges` and `interactive` rebase too. Upgit never stops to ask, and does
+// not recreate merges, so they rebase like `true`.
+pub fn parse_rebase(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "merges" | "m" | "interactive" | "i" | "preserve" | "p" => Some(true),
+        other => parse_bool(other),
+    }
+}
+
+pub fn parse_ff(value: &str) -> Option<FastForward> {
+    match value.to_lowercase().as_str() {
+        "only" => Some(FastForward::Only),
+        other => parse_bool(other).map(|ff| if ff { FastForward::Allow } else { FastForward::Never }),
+    }
+}
+
+// The first of the keys that is set and valid. A key with no value, like
+// `[pull] rebase`, is true to git.
+fn lookup<T>(config: &git2::Config, keys: &[String], parse: fn(&str) -> Option<T>) -> Option<T> {
+    keys.iter().find_map(|key| match config.get_bool(key) {
+        Ok(value) => parse(&value.to_string()),
+        Err(_) => config.get_string(key).ok().and_then(|x| parse(&x)),
+    })
+}
+
+impl Overrides {
+    pub fn strategy(&self, config: &git2::Config, branch: &str, remote: &str) -> Strategy {
+        let rebase_keys = [format!("branch.{}.rebase", branch), String::from("pull.rebase")];
+        // `git pull` reads pull.ff, and the merge it runs falls back to merge.ff.
+        let ff_keys = [String::from("pull.ff"), String::from("merge.ff")];
+        let prune_keys = [format!("remote.{}.prune", remote), String::from("fetch.prune")];
+        Strategy {
+            rebase: self.rebase.or_else(|| lookup(config, &rebase_keys, parse_rebase)).unwrap_or(false),
+            ff: self.ff.or_else(|| lookup(config, &ff_keys, parse_ff)).unwrap_or(FastForward::Allow),
+            prune: self.prune.or_else(|| lookup(config, &prune_keys, parse_bool)).unwrap_or(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempPath;
+
+    fn config(name: &str, contents: &str) -> (TempPath, git2::Config) {
+        let path = TempPath::file(&format!("pull-{}", name), contents);
+        let config = git2::Config::open(path.path()).unwrap();
+        (path, config)
+    }
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(parse_rebase("merges"), Some(true));
+        assert_eq!(parse_rebase("False"), Some(false));
+        assert_eq!(parse_rebase("sometimes"), None);
+        assert_eq!(parse_ff("only"), Some(FastForward::Only));
+        assert_eq!(parse_ff("false"), Some(FastForward::Never));
+        assert_eq!(parse_ff("yes"), Some(FastForward::Allow));
+    }
+
+    #[test]
+    fn defaults_to_fast_forward_or_merge() {
+        let (_path, config) = config("defaults", "");
+        assert_eq!(Overrides::default().strategy(&config, "main", "origin"), Strategy {
+            rebase: false,
+            ff: FastForward::Allow,
+            prune: false,
+        });
+    }
+
+    #[test]
+    fn branch_and_remote_settings_win() {
+        let (_path, config) = config("branch", "\
+[pull]\n\trebase = true\n\tff = only\n\
+[merge]\n\tff = false\n\
+[branch \"main\"]\n\trebase = false\n\
+[fetch]\n\tprune = false\n\
+[remote \"origin\"]\n\tprune\n");
+        let strategy = Overrides::default().strategy(&config, "main", "origin");
+        assert_eq!(strategy, Strategy { rebase: false, ff: FastForward::Only, prune: true });
+        assert!(Overrides::default().strategy(&config, "dev", "origin").rebase);
+    }
+
+    #[test]
+    fn merge_ff_is_the_fallback() {
+        let (_path, config) = config("merge-ff", "[merge]\n\tff = false\n");
+        assert_eq!(Overrides::default().strategy(&config, "main", "origin").ff, FastForward::Never);
+    }
+
+    #[test]
+    fn flags_override_config() {
+        let (_path, config) = config("flags", "[pull]\n\trebase = true\n\tff = only\n");
+        let overrides = Overrides { rebase: Some(false), ff: Some(FastForward::Allow), prune: Some(true) };
+        assert_eq!(overrides.strategy(&config, "main", "origin"), Strategy {
+            rebase: false,
+            ff: FastForward::Allow,
+            prune: true,
+        });
+    }
+}
@@ -0,0 +1,157 @@
+use git2::{ErrorClass, ErrorCode};
+use openssl::rand::rand_bytes;
+use std::time::Duration;
+
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Kind {
+    Transient,
+    Permanent,
+}
+
+// What libgit2, libssh2 and the OS say when a connection could not be made or
+// broke off. Other network, ssh and OS errors include local IO failures and
+// messages from the server, like a repo that does not exist, so they are not
+// worth retrying.
+const CONNECTION_ERRORS: &[&str] = &[
+    "failed to resolve address",
+    "temporary failure in name resolution",
+    "failed to connect",
+    "connection refused",
+    "connection reset",
+    "connection aborted",
+    "connection closed",
+    "broken pipe",
+    "network is unreachable",
+    "no route to host",
+    "timed out",
+    "early eof",
+    "failed getting banner",
+    "unable to exchange encryption keys",
+];
+
+fn is_connection_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    CONNECTION_ERRORS.iter().any(|x| message.contains(x))
+}
+
+// libgit2 only reports the status code in the message.
+fn http_status(message: &str) -> Option<u16> {
+    message.strip_prefix("unexpected http status code: ")?.trim().parse().ok()
+}
+
+// Anything that could go away by itself on the next try is transient. When in
+// doubt an error is permanent, so a broken repo is not fetched over and over.
+pub fn classify(err: &git2::Error) -> Kind {
+    match err.code() {
+        ErrorCode::Auth | ErrorCode::Certificate | ErrorCode::NotFound | ErrorCode::Owner => return Kind::Permanent,
+        // The connection closed in the middle of the transfer.
+        ErrorCode::Eof => return Kind::Transient,
+        _ => {},
+    };
+    match err.class() {
+        ErrorClass::Http => match http_status(err.message()) {
+            Some(429) => Kind::Transient,
+            Some(status) if (500..600).contains(&status) => Kind::Transient,
+            _ => Kind::Permanent,
+        },
+        _ if is_connection_error(err.message()) => Kind::Transient,
+        _ => Kind::Permanent,
+    }
+}
+
+// Doubles with every attempt, up to a cap. `jitter` is between 0 and 1 and picks
+// a delay between half and all of that, so repos that failed together do not
+// all retry at the same moment.
+fn delay(attempt: u32, jitter: f64) -> Duration {
+    let full = BASE_DELAY
+        .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .unwrap_or(MAX_DELAY)
+        .min(MAX_DELAY);
+    full.div_f64(2.0).mul_f64(1.0 + jitter.clamp(0.0, 1.0))
+}
+
+fn jitter() -> f64 {
+    let mut buf = [0u8; 4];
+    match rand_bytes(&mut buf) {
+        Ok(_) => f64::from(u32::from_le_bytes(buf)) / f64::from(u32::MAX),
+        Err(_) => 0.5,
+    }
+}
+
+// How long to wait after the given (1 based) attempt failed.
+pub fn backoff(attempt: u32) -> Duration {
+    delay(attempt, jitter())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(code: ErrorCode, class: ErrorClass, message: &str) -> git2::Error {
+        git2::Error::new(code, class, message)
+    }
+
+    #[test]
+    fn network_errors_are_transient() {
+        let err = error(ErrorCode::GenericError, ErrorClass::Net, "failed to resolve address for github.com");
+        assert_eq!(classify(&err), Kind::Transient);
+        let err = error(ErrorCode::GenericError, ErrorClass::Ssh, "Failed getting banner");
+        assert_eq!(classify(&err), Kind::Transient);
+        let err = error(ErrorCode::Eof, ErrorClass::Net, "early EOF");
+        assert_eq!(classify(&err), Kind::Transient);
+        let err = error(ErrorCode::GenericError, ErrorClass::Os, "failed to connect to git.corp: Connection refused");
+        assert_eq!(classify(&err), Kind::Transient);
+    }
+
+    #[test]
+    fn other_network_errors_are_permanent() {
+        let err = error(ErrorCode::GenericError, ErrorClass::Ssh, "ERROR: Repository not found.");
+        assert_eq!(classify(&err), Kind::Permanent);
+        let err = error(ErrorCode::GenericError, ErrorClass::Os, "failed to write to file '.git/FETCH_HEAD': No space left on device");
+        assert_eq!(classify(&err), Kind::Permanent);
+        let err = error(ErrorCode::GenericError, ErrorClass::Net, "invalid packet line");
+        assert_eq!(classify(&err), Kind::Permanent);
+    }
+
+    #[test]
+    fn http_status_codes() {
+        let status = |code| error(ErrorCode::GenericError, ErrorClass::Http, &format!("unexpected http status code: {}", code));
+        assert_eq!(classify(&status(429)), Kind::Transient);
+        assert_eq!(classify(&status(502)), Kind::Transient);
+        assert_eq!(classify(&status(503)), Kind::Transient);
+        assert_eq!(classify(&status(404)), Kind::Permanent);
+        assert_eq!(classify(&status(403)), Kind::Permanent);
+    }
+
+    #[test]
+    fn auth_and_not_found_are_permanent() {
+        let err = error(ErrorCode::Auth, ErrorClass::Ssh, "Failed to authenticate SSH session");
+        assert_eq!(classify(&err), Kind::Permanent);
+        let err = error(ErrorCode::Auth, ErrorClass::Http, "unexpected authentication failure");
+        assert_eq!(classify(&err), Kind::Permanent);
+        let err = error(ErrorCode::NotFound, ErrorClass::Net, "repository not found");
+        assert_eq!(classify(&err), Kind::Permanent);
+        let err = error(ErrorCode::GenericError, ErrorClass::Reference, "reference is not a tree");
+        assert_eq!(classify(&err), Kind::Permanent);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        assert_eq!(delay(1, 1.0), Duration::from_secs(1));
+        assert_eq!(delay(2, 1.0), Duration::from_secs(2));
+        assert_eq!(delay(3, 1.0), Duration::from_secs(4));
+        assert_eq!(delay(3, 0.0), Duration::from_secs(2));
+        assert_eq!(delay(50, 1.0), MAX_DELAY);
+    }
+
+    #[test]
+    fn backoff_stays_in_range() {
+        for attempt in 1..8 {
+            let wait = backoff(attempt);
+            assert!(wait >= delay(attempt, 0.0) && wait <= delay(attempt, 1.0));
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use crate::end;
+
+const HEADER: &str = "upgit-state 3";
+// The last line once a run got to its summary.
+const COMPLETE: &str = "complete";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rerun {
+    All,
+    OnlyFailed,
+    Resume,
+}
+
+// What the last run left behind. A run is complete unless it was cancelled or
+// killed before it got to the summary.
+#[derive(Debug, Clone)]
+pub struct State {
+    pub complete: bool,
+    pub ends: Vec<end::End>,
+}
+
+// Which of the listed repos to update this time, and which earlier results
+// still stand.
+#[derive(Debug)]
+pub struct Plan {
+    pub run: Vec<String>,
+    pub kept: Vec<end::End>,
+}
+
+pub fn default_path() -> String {
+    let state_home = env::var("XDG_STATE_HOME")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| format!("{}/.local/state", env::var("HOME").expect("Unable to find HOME env var")));
+    format!("{}/upgit/last-run", state_home)
+}
+
+// Cancelled repos were either never started or stopped before their merge.
+fn finished(status: &end::Status) -> bool {
+    status != &end::Status::Cancelled
+}
+
+pub fn plan(rerun: &Rerun, previous: Option<State>, repos: Vec<String>) -> Plan {
+    let previous = match (rerun, previous) {
+        (Rerun::All, _) | (_, None) => return Plan { run: repos, kept: vec![] },
+        (Rerun::Resume, Some(state)) if state.complete => return Plan { run: repos, kept: vec![] },
+        (_, Some(state)) => state,
+    };
+    let (kept, redo): (Vec<_>, Vec<_>) = previous.ends.into_iter().partition(|x| match rerun {
+        Rerun::OnlyFailed => x.status().succeeded(),
+        _ => finished(x.status()),
+    });
+    let run: Vec<String> = match rerun {
+        Rerun::OnlyFailed => {
+            let failed: HashSet<_> = redo.iter().map(|x| x.path()).collect();
+            repos.into_iter().filter(|x| failed.contains(x.as_str())).collect()
+        },
+        _ => {
+            let done: HashSet<_> = kept.iter().map(|x| x.path()).collect();
+            repos.into_iter().filter(|x| !done.contains(x.as_str())).collect()
+        },
+    };
+    let running: HashSet<_> = run.iter().cloned().collect();
+    // Failures that are not being redone, e.g. from another git dir, stay recorded.
+    let kept = kept.into_iter().chain(redo).filter(|x| !running.contains(x.path())).collect();
+    Plan { run, kept }
+}
+
+// Reports span several lines, so each field is escaped onto one.
+fn escape(x: &str) -> String {
+    x.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(x: &str) -> String {
+    let mut out = String::with_capacity(x.len());
+    let mut chars = x.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn format_line(x: &end::End) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\n",
+        x.status().as_name(),
+        x.attempts(),
+        escape(x.path()),
+        escape(x.report()),
+    )
+}
+
+fn format(ends: &[end::End]) -> String {
+    let mut out = format!("{}\n", HEADER);
+    for x in ends {
+        out.push_str(&format_line(x));
+    }
+    out
+}
+
+fn parse_line(line: &str) -> Option<end::End> {
+    let fields: Vec<_> = line.split('\t').collect();
+    if fields.len() != 4 {
+        return None;
+    }
+    let status = end::Status::from_name(fields[0])?;
+    let attempts = fields[1].parse::<u32>().ok()?;
+    Some(end::with_path(unescape(fields[2]))(status, unescape(fields[3])).with_attempts(attempts))
+}
+
+// A file from some other version is ignored. A line that can not be read,
+// like one left unfinished by a run killed while appending, only loses that
+// repo, which is then updated again.
+fn parse(contents: &str) -> Option<State> {
+    let mut lines: Vec<_> = contents.lines().collect();
+    if !contents.ends_with('\n') {
+        lines.pop();
+    }
+    if lines.first() != Some(&HEADER) {
+        return None;
+    }
+    let complete = lines.last() == Some(&COMPLETE);
+    if complete {
+        lines.pop();
+    }
+    let ends = lines[1..].iter().filter_map(|x| parse_line(x)).collect();
+    Some(State { complete, ends })
+}
+
+pub fn load(path: &str) -> Option<State> {
+    fs::read_to_string(path).ok().and_then(|x| parse(&x))
+}
+
+// Written to the side and renamed, so a run killed mid-write leaves the old
+// state. The file is then kept open to append to.
+fn start(path: &str, kept: &[end::End]) -> io::Result<fs::File> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, format(kept))?;
+    fs::rename(&tmp, path)?;
+    fs::OpenOptions::new().append(true).open(path)
+}
+
+// Appends each repo as it ends, so even a run that is killed can be resumed.
+pub struct Recorder {
+    path: String,
+    file: Option<fs::File>,
+}
+
+impl Recorder {
+    pub fn new(path: String, kept: Vec<end::End>) -> Recorder {
+        let file = start(&path, &kept);
+        let mut recorder = Recorder { path, file: None };
+        match file {
+            Ok(file) => recorder.file = Some(file),
+            Err(err) => recorder.warn(err),
+        }
+        recorder
+    }
+
+    pub fn record(&mut self, end: &end::End) {
+        self.append(&format_line(end));
+    }
+
+    pub fn finish(&mut self, complete: bool) {
+        if complete {
+            self.append(&format!("{}\n", COMPLETE));
+        }
+    }
+
+    fn append(&mut self, line: &str) {
+        let written = match &mut self.file {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => return,
+        };
+        if let Err(err) = written {
+            self.warn(err);
+        }
+    }
+
+    // Once is enough, nothing more is saved after that.
+    fn warn(&mut self, err: io::Error) {
+        eprintln!("\nCould not save run state to {}: {}", self.path, err);
+        self.file = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempPath;
+
+    fn mk_end(path: &str, status: end::Status) -> end::End {
+        end::with_path(String::from(path))(status, String::from(""))
+    }
+
+    fn paths(ends: &[end::End]) -> Vec<&str> {
+        ends.iter().map(|x| x.path()).collect()
+    }
+
+    fn repos(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let ends = vec![
+            end::with_path(String::from("/g/a\tb"))(end::Status::Dirty, String::from("wt Δ: x\n    new: y\\z")).with_attempts(3),
+            mk_end("/g/c", end::Status::UpToDate),
+            mk_end("/g/d", end::Status::WIPOther),
+        ];
+        let state = parse(&format(&ends)).unwrap();
+        assert!(!state.complete);
+        assert_eq!(state.ends.len(), 3);
+        assert_eq!(state.ends[0].path(), "/g/a\tb");
+        assert_eq!(state.ends[0].status(), &end::Status::Dirty);
+        assert_eq!(state.ends[0].report(), "wt Δ: x\n    new: y\\z");
+        assert_eq!(state.ends[0].attempts(), 3);
+        assert_eq!(state.ends[1].status(), &end::Status::UpToDate);
+        assert_eq!(state.ends[2].status(), &end::Status::WIPOther);
+    }
+
+    #[test]
+    fn ignores_unknown_files() {
+        assert!(parse("").is_none());
+        assert!(parse("upgit-state 2\ncomplete\n").is_none());
+    }
+
+    #[test]
+    fn records_by_appending() {
+        let temp = TempPath::new("state");
+        let path = temp.path().display().to_string();
+        let mut recorder = Recorder::new(path.clone(), vec![mk_end("/g/kept", end::Status::UpToDate)]);
+        recorder.record(&mk_end("/g/a", end::Status::Updated));
+        let state = load(&path).unwrap();
+        assert!(!state.complete);
+        assert_eq!(paths(&state.ends), vec!["/g/kept", "/g/a"]);
+        recorder.finish(true);
+        let state = load(&path).unwrap();
+        assert!(state.complete);
+        assert_eq!(paths(&state.ends), vec!["/g/kept", "/g/a"]);
+    }
+
+    #[test]
+    fn skips_lines_it_can_not_read() {
+        let state = parse("upgit-state 3\nsomething\t1\t/g/a\t\nup_to_date\t1\t/g/b\t\ncomplete\n").unwrap();
+        assert!(state.complete);
+        assert_eq!(paths(&state.ends), vec!["/g/b"]);
+    }
+
+    #[test]
+    fn drops_a_line_cut_off_by_a_kill() {
+        let state = parse("upgit-state 3\nupdated\t1\t/g/a\t\nupda").unwrap();
+        assert!(!state.complete);
+        assert_eq!(paths(&state.ends), vec!["/g/a"]);
+    }
+
+    #[test]
+    fn only_failed_reruns_failures_that_are_still_listed() {
+        let previous = State {
+            complete: true,
+            ends: vec![
+                mk_end("/g/a", end::Status::UpToDate),
+                mk_end("/g/b", end::Status::FailedFetch),
+                mk_end("/g/c", end::Status::Updated),
+                mk_end("/g/d", end::Status::Cancelled),
+                mk_end("/other/e", end::Status::AuthRequired),
+            ],
+        };
+        let plan = plan(&Rerun::OnlyFailed, Some(previous), repos(&["/g/a", "/g/b", "/g/c", "/g/d", "/g/new"]));
+        assert_eq!(plan.run, repos(&["/g/b", "/g/d"]));
+        assert_eq!(paths(&plan.kept), vec!["/g/a", "/g/c", "/other/e"]);
+    }
+
+    #[test]
+    fn resume_skips_finished_repos() {
+        let previous = State {
+            complete: false,
+            ends: vec![
+                mk_end("/g/a", end::Status::UpToDate),
+                mk_end("/g/b", end::Status::FailedFetch),
+                mk_end("/g/c", end::Status::Cancelled),
+            ],
+        };
+        let plan = plan(&Rerun::Resume, Some(previous), repos(&["/g/a", "/g/b", "/g/c", "/g/d"]));
+        assert_eq!(plan.run, repos(&["/g/c", "/g/d"]));
+        assert_eq!(paths(&plan.kept), vec!["/g/a", "/g/b"]);
+    }
+
+    #[test]
+    fn resume_after_complete_run_updates_everything() {
+        let previous = State {
+            complete: true,
+            ends: vec![mk_end("/g/a", end::Status::UpToDate)],
+        };
+        let plan = plan(&Rerun::Resume, Some(previous), repos(&["/g/a", "/g/b"]));
+        assert_eq!(plan.run, repos(&["/g/a", "/g/b"]));
+        assert!(plan.kept.is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Tests run on several threads, so the process id alone is not unique.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// A file or folder under the temp dir that is removed when dropped, so a
+// failing test does not leave it behind.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> TempPath {
+        let n = NEXT.fetch_add(1, Ordering::SeqCst);
+        TempPath(std::env::temp_dir().join(format!("upgit-{}-{}-{}", name, std::process::id(), n)))
+    }
+
+    pub fn file(name: &str, contents: &str) -> TempPath {
+        let temp = TempPath::new(name);
+        fs::write(temp.path(), contents).unwrap();
+        temp
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() { fs::remove_dir_all(&self.0) } else { fs::remove_file(&self.0) };
+    }
+}
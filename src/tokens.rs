@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+// Personal access tokens, one per host. They are only ever offered to the host
+// they were given for, unlike --default-plain.
+
+const ENV_PREFIX: &str = "UPGIT_TOKEN_";
+
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    by_host: HashMap<String, String>, // exact lowercased host
+}
+
+// Env var names can not hold dots or dashes, so env vars spell them as `_` and
+// `__`: github.com is UPGIT_TOKEN_github_com, and git-corp.io is
+// UPGIT_TOKEN_git__corp_io. Each name stands for exactly one host.
+fn host_from_env_name(name: &str) -> String {
+    name.to_lowercase().replace("__", "-").replace('_', ".")
+}
+
+impl Tokens {
+    // UPGIT_TOKEN_<host> variables.
+    pub fn from_env(vars: impl Iterator<Item = (String, String)>) -> Tokens {
+        let by_host = vars.filter_map(|(name, token)| {
+            let host = name.strip_prefix(ENV_PREFIX)?;
+            if host.is_empty() || token.is_empty() {
+                return None;
+            }
+            Some((host_from_env_name(host), token))
+        }).collect();
+        Tokens { by_host }
+    }
+
+    // Hosts in the file win over env vars.
+    pub fn add_file(&mut self, contents: &str) -> Result<(), String> {
+        self.by_host.extend(parse_file(contents)?);
+        Ok(())
+    }
+
+    pub fn get(&self, host: &str) -> Option<&String> {
+        self.by_host.get(&host.to_lowercase())
+    }
+}
+
+// A file of <host>=<token> lines. Blank lines and lines starting with `#` are skipped.
+fn parse_file(contents: &str) -> Result<HashMap<String, String>, String> {
+    let mut tokens = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((host, token)) if !host.trim().is_empty() && !token.trim().is_empty() => {
+                tokens.insert(host.trim().to_lowercase(), token.trim().to_string());
+            },
+            _ => return Err(format!("line {} should be <domain>=<token>", i + 1)),
+        }
+    }
+    Ok(tokens)
+}
+
+// The username each forge expects alongside a token. Other hosts get the
+// username found for the remote like they would for a password.
+pub fn forge_username(host: &str) -> Option<&'static str> {
+    let host = host.to_lowercase();
+    if host == "github.com" || host.ends_with(".github.com") || host.starts_with("github.") {
+        Some("x-access-token")
+    } else if host == "gitlab.com" || host.starts_with("gitlab.") {
+        Some("oauth2")
+    } else if host == "bitbucket.org" {
+        Some("x-token-auth")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_env_vars() {
+        let vars = vec![
+            (String::from("UPGIT_TOKEN_github_com"), String::from("ghp_1")),
+            (String::from("UPGIT_TOKEN_GIT__CORP_IO"), String::from("corp")),
+            (String::from("UPGIT_TOKEN_empty_com"), String::from("")),
+            (String::from("UPGIT_TOKENS"), String::from("/tmp/tokens")),
+            (String::from("HOME"), String::from("/root")),
+        ];
+        let tokens = Tokens::from_env(vars.into_iter());
+        assert_eq!(tokens.by_host.len(), 2);
+        assert_eq!(tokens.get("GitHub.com").map(String::as_str), Some("ghp_1"));
+        assert_eq!(tokens.get("git-corp.io").map(String::as_str), Some("corp"));
+    }
+
+    #[test]
+    fn env_vars_name_one_host() {
+        let vars = vec![
+            (String::from("UPGIT_TOKEN_GIT_CORP_IO"), String::from("dots")),
+            (String::from("UPGIT_TOKEN_my__company_com"), String::from("dash")),
+        ];
+        let tokens = Tokens::from_env(vars.into_iter());
+        assert_eq!(tokens.get("git.corp.io").map(String::as_str), Some("dots"));
+        assert_eq!(tokens.get("git-corp.io"), None);
+        assert_eq!(tokens.get("git_corp.io"), None);
+        assert_eq!(tokens.get("my-company.com").map(String::as_str), Some("dash"));
+        assert_eq!(tokens.get("my.company.com"), None);
+    }
+
+    #[test]
+    fn file_tokens_need_the_exact_host() {
+        let mut tokens = Tokens::from_env(vec![(String::from("UPGIT_TOKEN_github_com"), String::from("env"))].into_iter());
+        tokens.add_file("# forges\ngithub.com = ghp_1\n\nGit.Corp.io=corp\n").unwrap();
+        assert_eq!(tokens.get("github.com").map(String::as_str), Some("ghp_1"));
+        assert_eq!(tokens.get("git.corp.io").map(String::as_str), Some("corp"));
+        assert_eq!(tokens.get("git-corp.io"), None);
+        assert_eq!(tokens.get("git_corp.io"), None);
+        assert_eq!(tokens.add_file("github.com\n"), Err(String::from("line 1 should be <domain>=<token>")));
+    }
+
+    #[test]
+    fn forge_usernames() {
+        assert_eq!(forge_username("GitHub.com"), Some("x-access-token"));
+        assert_eq!(forge_username("gitlab.corp.io"), Some("oauth2"));
+        assert_eq!(forge_username("bitbucket.org"), Some("x-token-auth"));
+        assert_eq!(forge_username("git.corp.io"), None);
+    }
+}
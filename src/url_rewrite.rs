@@ -0,0 +1,138 @@
+// git's url.<base>.insteadOf and url.<base>.pushInsteadOf. Upgit rewrites
+// remote urls itself, so fetching, credentials, host limits and reports all
+// use the url git would, whatever libgit2 makes of the same config.
+
+#[derive(Debug, Clone, Default)]
+pub struct Rewrites {
+    fetch: Vec<(String, String)>, // (prefix, base)
+    push: Vec<(String, String)>,
+}
+
+// The base that replaces the longest matching prefix. On a tie the first one
+// configured wins, as in git.
+fn rewrite(rules: &[(String, String)], url: &str) -> Option<String> {
+    let mut best: Option<&(String, String)> = None;
+    for rule in rules.iter().filter(|(prefix, _)| url.starts_with(prefix.as_str())) {
+        if best.is_none_or(|x| rule.0.len() > x.0.len()) {
+            best = Some(rule);
+        }
+    }
+    best.map(|(prefix, base)| format!("{}{}", base, &url[prefix.len()..]))
+}
+
+impl Rewrites {
+    pub fn from_config(config: &git2::Config) -> Rewrites {
+        let mut rewrites = Rewrites::default();
+        let entries = match config.entries(Some("url\\..*\\.(push)?insteadof")) {
+            Ok(x) => x,
+            Err(_) => return rewrites,
+        };
+        let _ = entries.for_each(|entry| {
+            let (name, prefix) = match (entry.name(), entry.value()) {
+                (Some(name), Some(prefix)) => (name, prefix.to_string()),
+                _ => return,
+            };
+            // The base is the subsection, so it keeps its case while the rest is lowercased.
+            let base = name.strip_prefix("url.").unwrap_or(name);
+            if let Some(base) = base.strip_suffix(".pushinsteadof") {
+                rewrites.push.push((prefix, base.to_string()));
+            } else if let Some(base) = base.strip_suffix(".insteadof") {
+                rewrites.fetch.push((prefix, base.to_string()));
+            }
+        });
+        rewrites
+    }
+
+    pub fn fetch_url(&self, url: &str) -> String {
+        rewrite(&self.fetch, url).unwrap_or_else(|| url.to_string())
+    }
+
+    // A remote's pushurl only gets insteadOf. Without one, its url gets
+    // pushInsteadOf, falling back to insteadOf.
+    pub fn push_url(&self, url: &str, pushurl: Option<&str>) -> String {
+        match pushurl {
+            Some(pushurl) => self.fetch_url(pushurl),
+            None => rewrite(&self.push, url).unwrap_or_else(|| self.fetch_url(url)),
+        }
+    }
+}
+
+// The urls a remote is fetched from and pushed to, after rewriting.
+pub fn remote_urls(repo: &git2::Repository, remote: &git2::Remote) -> Option<(String, String)> {
+    let config = repo.config().ok()?;
+    let name = remote.name()?;
+    let url = config.get_string(&format!("remote.{}.url", name)).ok()?;
+    let pushurl = config.get_string(&format!("remote.{}.pushurl", name)).ok();
+    let rewrites = Rewrites::from_config(&config);
+    Some((rewrites.fetch_url(&url), rewrites.push_url(&url, pushurl.as_deref())))
+}
+
+// A remote that fetches from url as given. libgit2 applies insteadOf again to
+// a new remote's url and git2 has no way to skip that, so with chained rules
+// refuse rather than fetch from a url other than the one reported.
+pub fn anonymous_remote<'r>(repo: &'r git2::Repository, url: &str) -> Result<git2::Remote<'r>, git2::Error> {
+    let remote = repo.remote_anonymous(url)?;
+    match remote.url() {
+        Some(x) if x == url => Ok(remote),
+        x => Err(git2::Error::from_str(&format!("libgit2 rewrites it again to {}", x.unwrap_or("an unreadable url")))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempPath;
+
+    fn rewrites(name: &str, contents: &str) -> Rewrites {
+        let path = TempPath::file(&format!("url-rewrite-{}", name), contents);
+        Rewrites::from_config(&git2::Config::open(path.path()).unwrap())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let rewrites = rewrites("longest", "\
+[url \"git@github.com:\"]\n\tinsteadOf = https://github.com/\n\
+[url \"git@github.com:Mirror/\"]\n\tinsteadOf = https://github.com/corp/\n");
+        assert_eq!(rewrites.fetch_url("https://github.com/org/repo.git"), "git@github.com:org/repo.git");
+        assert_eq!(rewrites.fetch_url("https://github.com/corp/repo.git"), "git@github.com:Mirror/repo.git");
+        assert_eq!(rewrites.fetch_url("https://gitlab.com/org/repo.git"), "https://gitlab.com/org/repo.git");
+    }
+
+    #[test]
+    fn push_rewrites() {
+        let rewrites = rewrites("push", "\
+[url \"https://mirror.corp/\"]\n\tinsteadOf = https://github.com/\n\
+[url \"git@github.com:\"]\n\tpushInsteadOf = https://github.com/\n");
+        let url = "https://github.com/org/repo.git";
+        assert_eq!(rewrites.fetch_url(url), "https://mirror.corp/org/repo.git");
+        assert_eq!(rewrites.push_url(url, None), "git@github.com:org/repo.git");
+        assert_eq!(rewrites.push_url(url, Some("https://github.com/org/fork.git")), "https://mirror.corp/org/fork.git");
+        assert_eq!(rewrites.push_url("https://gitlab.com/x.git", None), "https://gitlab.com/x.git");
+    }
+
+    #[test]
+    fn several_prefixes_for_a_base() {
+        let rewrites = rewrites("multi", "[url \"git@git.corp:\"]\n\tinsteadOf = corp:\n\tinsteadOf = https://git.corp/\n");
+        assert_eq!(rewrites.fetch_url("corp:team/repo"), "git@git.corp:team/repo");
+        assert_eq!(rewrites.fetch_url("https://git.corp/team/repo"), "git@git.corp:team/repo");
+    }
+
+    #[test]
+    fn chained_rules_apply_once() {
+        let dir = TempPath::new("url-rewrite-chain");
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("url.https://b.example/.insteadOf", "https://a.example/").unwrap();
+        config.set_str("url.https://c.example/.insteadOf", "https://b.example/").unwrap();
+        repo.remote("origin", "https://a.example/repo.git").unwrap();
+        let remote = repo.find_remote("origin").unwrap();
+        let (fetch, push) = remote_urls(&repo, &remote).unwrap();
+        assert_eq!(fetch, "https://b.example/repo.git");
+        assert_eq!(push, "https://b.example/repo.git");
+        // libgit2 agrees for the named remote, but would rewrite git's url again.
+        assert_eq!(remote.url(), Some(fetch.as_str()));
+        assert!(anonymous_remote(&repo, &fetch).is_err());
+        let other = anonymous_remote(&repo, "https://d.example/repo.git").unwrap();
+        assert_eq!(other.url(), Some("https://d.example/repo.git"));
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::Path;
+use crate::end;
+use crate::string_ops;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    repo: Option<String>, // matches the repo's folder name or full path
+    pattern: String,
+}
+
+// Paths that need a person to look at them when an update touches them, such
+// as lock files, migrations or CI config.
+#[derive(Debug, Clone, Default)]
+pub struct Watch {
+    rules: Vec<Rule>,
+}
+
+// Like .gitignore, a pattern without a slash matches a file name at any depth,
+// and one with a slash matches from the repo root. `*` also matches across
+// folders, so `migrations/**` is everything under migrations.
+pub fn pattern_matches(pattern: &str, file: &str) -> bool {
+    let pattern = pattern.trim_start_matches('/');
+    if pattern.contains('/') {
+        return string_ops::wildcard_match(pattern, file);
+    }
+    let name = Path::new(file).file_name().and_then(|x| x.to_str()).unwrap_or(file);
+    string_ops::wildcard_match(pattern, name)
+}
+
+pub fn repo_matches(repo: &str, repo_path: &str) -> bool {
+    let name = Path::new(repo_path).file_name().and_then(|x| x.to_str()).unwrap_or(repo_path);
+    string_ops::wildcard_match(repo, name) || string_ops::wildcard_match(repo, repo_path)
+}
+
+impl Watch {
+    // Each entry is a pattern for every repo, or <repo>=<pattern> for one.
+    pub fn parse<'a>(entries: impl Iterator<Item = &'a str>) -> Watch {
+        let rules = entries.map(str::trim).filter(|x| !x.is_empty()).map(|entry| match entry.split_once('=') {
+            Some((repo, pattern)) => Rule { repo: Some(repo.to_string()), pattern: pattern.to_string() },
+            None => Rule { repo: None, pattern: entry.to_string() },
+        }).collect();
+        Watch { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // The changed files that match a rule for this repo. A rename counts if
+    // either side of it matches.
+    pub fn touched(&self, repo_path: &str, changes: &[end::Change]) -> Vec<String> {
+        let rules: Vec<_> = self.rules.iter()
+            .filter(|x| x.repo.as_ref().is_none_or(|repo| repo_matches(repo, repo_path)))
+            .collect();
+        changes.iter().filter_map(|change| {
+            let mut files = std::iter::once(&change.path).chain(change.old_path.iter());
+            if files.any(|file| rules.iter().any(|rule| pattern_matches(&rule.pattern, file))) {
+                Some(change.path.clone())
+            } else {
+                None
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(path: &str, old_path: Option<&str>) -> end::Change {
+        end::Change {
+            change: "modified",
+            path: path.to_string(),
+            old_path: old_path.map(String::from),
+            insertions: 1,
+            deletions: 0,
+            binary: false,
+        }
+    }
+
+    #[test]
+    fn bare_names_match_at_any_depth() {
+        assert!(pattern_matches("Cargo.lock", "Cargo.lock"));
+        assert!(pattern_matches("Cargo.lock", "crates/core/Cargo.lock"));
+        assert!(pattern_matches("CODEOWNERS", ".github/CODEOWNERS"));
+        assert!(!pattern_matches("Cargo.lock", "Cargo.lock.bak"));
+    }
+
+    #[test]
+    fn slashed_patterns_match_from_root() {
+        assert!(pattern_matches("migrations/**", "migrations/2020/01_init.sql"));
+        assert!(pattern_matches(".github/workflows/**", ".github/workflows/ci.yml"));
+        assert!(!pattern_matches("migrations/**", "db/migrations/01_init.sql"));
+        assert!(pattern_matches("/migrations/*.sql", "migrations/01_init.sql"));
+    }
+
+    #[test]
+    fn rules_can_be_per_repo() {
+        let watch = Watch::parse(vec!["Cargo.lock", "api=migrations/**"].into_iter());
+        let changes = vec![change("migrations/01.sql", None), change("Cargo.lock", None), change("src/main.rs", None)];
+        assert_eq!(watch.touched("/g/api", &changes), vec!["migrations/01.sql", "Cargo.lock"]);
+        assert_eq!(watch.touched("/g/web", &changes), vec!["Cargo.lock"]);
+    }
+
+    #[test]
+    fn renames_count_on_either_side() {
+        let watch = Watch::parse(vec!["CODEOWNERS"].into_iter());
+        assert_eq!(watch.touched("/g/a", &[change("docs/OWNERS", Some("CODEOWNERS"))]), vec!["docs/OWNERS"]);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::end;
+
+// How long past --timeout a fetch may go without finishing before it is given
+// up on. Progress callbacks abort slow fetches themselves, this is for the
+// ones that stall with nothing arriving, so no callback runs at all.
+const GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Fetching(Instant), // since the attempt started or a prompt was answered
+    Waiting, // on a prompt or a retry, which does not count
+    Updating, // past the fetch, changing the working tree
+    Abandoned, // must not get past the fetch
+}
+
+#[derive(Debug, Default)]
+struct State {
+    stages: HashMap<String, Stage>,
+    stopped: bool,
+}
+
+// Keeps track of where each running repo is, from outside its thread. libgit2
+// can block on a socket with no way to time out, so a stalled fetch is left
+// behind, and it is only ever safe to do that before the working tree changes.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    state: Arc<Mutex<State>>,
+    limit: Option<Duration>,
+}
+
+impl Watchdog {
+    pub fn new(fetch_timeout: Option<Duration>) -> Watchdog {
+        Watchdog {
+            state: Arc::new(Mutex::new(State::default())),
+            limit: fetch_timeout.map(|x| x + GRACE),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("could not acquire watchdog lock")
+    }
+
+    fn set(&self, repo_path: &str, stage: Stage) {
+        let mut state = self.state();
+        let stage = if state.stopped { Stage::Abandoned } else { stage };
+        let entry = state.stages.entry(repo_path.to_string()).or_insert(stage);
+        if *entry != Stage::Abandoned {
+            *entry = stage;
+        }
+    }
+
+    // A fetch attempt starts, or carries on after a prompt.
+    pub fn fetching(&self, repo_path: &str) {
+        self.set(repo_path, Stage::Fetching(Instant::now()));
+    }
+
+    pub fn waiting(&self, repo_path: &str) {
+        self.set(repo_path, Stage::Waiting);
+    }
+
+    pub fn is_abandoned(&self, repo_path: &str) -> bool {
+        self.state().stages.get(repo_path) == Some(&Stage::Abandoned)
+    }
+
+    // Moves the repo past fetching. False when it was given up on, and must
+    // stop before touching the working tree.
+    pub fn fetched(&self, repo_path: &str) -> bool {
+        self.set(repo_path, Stage::Updating);
+        !self.is_abandoned(repo_path)
+    }
+
+    pub fn done(&self, repo_path: &str) {
+        self.state().stages.remove(repo_path);
+    }
+
+    // Gives up on a fetch that has been going for too long, with the end to
+    // report for it.
+    pub fn stalled(&self, repo_path: &str) -> Option<end::End> {
+        let limit = self.limit?;
+        let mut state = self.state();
+        let stage = state.stages.get_mut(repo_path)?;
+        match *stage {
+            Stage::Fetching(since) if since.elapsed() > limit => {
+                *stage = Stage::Abandoned;
+                let msg = format!("No response from the remote for {} seconds, gave up on the fetch", since.elapsed().as_secs());
+                Some(end::with_path(repo_path.to_string())(end::Status::TimedOut, msg))
+            },
+            _ => None,
+        }
+    }
+
+    // Gives up on every repo that has not got past fetching, including ones
+    // that start later, and returns those that have, to be waited for.
+    pub fn stop(&self) -> Vec<String> {
+        let mut state = self.state();
+        state.stopped = true;
+        let mut updating = vec![];
+        for (path, stage) in state.stages.iter_mut() {
+            if *stage == Stage::Updating {
+                updating.push(path.clone());
+            } else {
+                *stage = Stage::Abandoned;
+            }
+        }
+        updating
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_up_on_stalled_fetches() {
+        let watchdog = Watchdog { state: Arc::default(), limit: Some(Duration::from_millis(10)) };
+        watchdog.fetching("/a");
+        assert!(watchdog.stalled("/a").is_none());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(watchdog.stalled("/a").map(|x| x.status().clone()), Some(end::Status::TimedOut));
+        assert!(watchdog.is_abandoned("/a"));
+        // It stays given up on when the fetch finally returns.
+        watchdog.fetching("/a");
+        assert!(!watchdog.fetched("/a"));
+    }
+
+    #[test]
+    fn waiting_does_not_count() {
+        let watchdog = Watchdog { state: Arc::default(), limit: Some(Duration::from_millis(10)) };
+        watchdog.waiting("/a");
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(watchdog.stalled("/a").is_none());
+        assert!(watchdog.fetched("/a"));
+        assert!(watchdog.stalled("/a").is_none());
+    }
+
+    #[test]
+    fn stop_keeps_repos_past_the_fetch() {
+        let watchdog = Watchdog::new(None);
+        watchdog.fetching("/fetching");
+        watchdog.fetching("/updating");
+        assert!(watchdog.fetched("/updating"));
+        assert_eq!(watchdog.stop(), vec![String::from("/updating")]);
+        assert!(!watchdog.fetched("/fetching"));
+        watchdog.fetching("/later");
+        assert!(watchdog.is_abandoned("/later"));
+    }
+}